@@ -21,7 +21,58 @@
   };
 
   outputs = { self, nixpkgs, crane, fenix, flake-utils, advisory-db, ... }:
-    flake-utils.lib.eachDefaultSystem (system:
+    {
+      nixosModules.default = { config, lib, pkgs, ... }:
+        let
+          cfg = config.services.gurl-agent;
+          gurl = self.packages.${pkgs.stdenv.hostPlatform.system};
+        in
+        {
+          options.services.gurl-agent = {
+            enable = lib.mkEnableOption "gurl agent, which keeps this host on its branch's derivation";
+
+            package = lib.mkOption {
+              type = lib.types.package;
+              default = gurl.default;
+              description = "The gurl package to use.";
+            };
+
+            settings = lib.mkOption {
+              type = lib.types.attrs;
+              default = { };
+              example = {
+                agent = {
+                  branch = "main";
+                  maintenance_windows = [{ days = [ "Sat" "Sun" ]; start = "22:00"; end = "05:00"; }];
+                  health_checks = [ "systemctl is-system-running" ];
+                };
+              };
+              description = "Contents of the gurl config file, see `src/config.rs`.";
+            };
+          };
+
+          config = lib.mkIf cfg.enable {
+            systemd.services.gurl-agent = {
+              description = "gurl agent";
+              wantedBy = [ "multi-user.target" ];
+              after = [ "network-online.target" ];
+              wants = [ "network-online.target" ];
+              # Switching restarts a lot of units, this one must not be one of them
+              restartIfChanged = false;
+              # bash is the `sh` the health checks run with
+              path = [ config.nix.package gurl.gurl-apply-helper pkgs.bash pkgs.hostname pkgs.sudo config.systemd.package ];
+              environment.GURL_CONFIG = "${pkgs.writeText "gurl-config.json" (builtins.toJSON cfg.settings)}";
+              serviceConfig = {
+                ExecStart = "${cfg.package}/bin/gurl agent";
+                Restart = "on-failure";
+                RestartSec = 30;
+                # Remembers the last store hash that failed to apply
+                StateDirectory = "gurl";
+              };
+            };
+          };
+        };
+    } // flake-utils.lib.eachDefaultSystem (system:
       let
         pkgs = nixpkgs.legacyPackages.${system};
 
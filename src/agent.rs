@@ -0,0 +1,232 @@
+use std::{fs, path::Path, process::Command, thread, time::Duration};
+
+use chrono::Local;
+use colored::Colorize;
+
+use crate::{
+    config::Config, copy_closure, group::resolve_entry, is_root, policy::Policy, reboot,
+    report_running_system, resolve_name, run_apply_helper, run_privileged, signature, ApplyOutcome,
+};
+
+/// Last store hash that failed to apply, kept across restarts of the agent.
+const FAILED_FILE: &str = "/var/lib/gurl/agent-failed";
+
+fn read_failed() -> Option<String> {
+    fs::read_to_string(FAILED_FILE)
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+fn save_failed(hash: Option<&str>) {
+    let result = match hash {
+        Some(hash) => Path::new(FAILED_FILE)
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(FAILED_FILE, hash)),
+        None if Path::new(FAILED_FILE).exists() => fs::remove_file(FAILED_FILE),
+        None => Ok(()),
+    };
+    if let Err(x) = result {
+        println!(
+            "WARN: {}",
+            format!("saving the failed store hash to {}: {}", FAILED_FILE, x).yellow()
+        );
+    }
+}
+
+pub fn handle_agent(once: bool) {
+    if !is_root() {
+        println!("ERROR: {}", "`gurl agent` has to run as root.".red());
+        std::process::exit(1);
+    }
+    let config = Config::load();
+    let host = resolve_name("$HOSTNAME".to_owned());
+    let name = config.agent.name.clone().unwrap_or(host.clone());
+    println!(
+        "INFO: agent following {} on the branch {}",
+        name.green(),
+        config.agent.branch
+    );
+
+    // Don't retry a store hash that already failed until the server has something new
+    let mut failed = read_failed();
+    if let Some(hash) = &failed {
+        println!("INFO: not retrying {}, it failed before", hash);
+    }
+    loop {
+        match poll(&host, &name, &config, failed.as_deref()) {
+            Round::Failed(hash) => {
+                save_failed(Some(&hash));
+                failed = Some(hash);
+            }
+            Round::Applied if failed.is_some() => {
+                save_failed(None);
+                failed = None;
+            }
+            _ => {}
+        }
+        if once {
+            break;
+        }
+        thread::sleep(Duration::from_secs(config.agent.poll_interval));
+    }
+}
+
+enum Round {
+    /// Nothing to do, or not now.
+    Idle,
+    Applied,
+    /// Applying this store hash failed.
+    Failed(String),
+}
+
+/// One round of the agent. Nothing in here exits, a failure only ends the round.
+fn poll(host: &str, name: &str, config: &Config, failed: Option<&str>) -> Round {
+    let publishers = &config.publishers;
+    let trusted_keys = &config.trusted_keys;
+    let substituters = &config.substituters;
+    let config = &config.agent;
+    let deriv = match resolve_entry(name, &config.branch) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("WARN: {}", x.yellow());
+            return Round::Idle;
+        }
+    };
+    let current_system = fs::read_link("/run/current-system")
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let policy = match Policy::get(host) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("WARN: {}, skipping this round", x.yellow());
+            return Round::Idle;
+        }
+    };
+    let now = Local::now();
+    let target = if policy.is_held(now) {
+        match &policy.pin {
+            Some(pin) => pin.clone(),
+            None => return Round::Idle,
+        }
+    } else {
+        deriv.storeHash.clone()
+    };
+    if target == current_system || failed == Some(target.as_str()) {
+        return Round::Idle;
+    }
+
+    let report = |outcome| {
+        report_running_system(
+            name.to_owned(),
+            config.branch.clone(),
+            outcome,
+            Some(target.clone()),
+        )
+    };
+
+    // A pin comes from the policy, not from an uploaded entry, so nobody signed it
+    let checked = if target != deriv.storeHash {
+        if publishers.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} is pinned to {}, which isn't a signed entry",
+                host, target
+            ))
+        }
+    } else {
+        signature::check_entry(&deriv, publishers).map(|_| ())
+    };
+    if let Err(x) = checked {
+        println!("ERROR: {}", format!("refusing to apply, {}", x).red());
+        report(Some(ApplyOutcome::VerificationFailed));
+        return Round::Failed(target);
+    }
+
+    // Prefetching is fine at any time, only the switch waits for a window
+    if !copy_closure(substituters, &target, None) {
+        report(Some(ApplyOutcome::CopyFailed));
+        return Round::Failed(target);
+    }
+    if let Err(x) = signature::check_toplevel(&target, trusted_keys) {
+        println!("ERROR: {}", format!("refusing to apply, {}", x).red());
+        report(Some(ApplyOutcome::VerificationFailed));
+        return Round::Failed(target);
+    }
+    // The windows on the server win over the local ones
+    let windows = if policy.maintenance_windows.is_empty() {
+        &config.maintenance_windows
+    } else {
+        &policy.maintenance_windows
+    };
+    if !windows.is_empty() && !windows.iter().any(|x| x.contains(now)) {
+        println!(
+            "INFO: {} is ready, waiting for a maintenance window",
+            target
+        );
+        return Round::Idle;
+    }
+
+    println!("INFO: switching to {}", target);
+    // gurl-apply-helper already went back to the previous generation
+    if !run_apply_helper(&target, None) {
+        report(Some(ApplyOutcome::ActivationFailed));
+        return Round::Failed(target);
+    }
+    if !health_checks_pass(&config.health_checks) {
+        if config.rollback_on_failure {
+            rollback();
+        }
+        report(Some(ApplyOutcome::HealthCheckFailed));
+        return Round::Failed(target);
+    }
+    println!("INFO: {}", "Successfully instaleld the closure!".green());
+    reboot::warn_if_required();
+    if target == deriv.storeHash {
+        signature::record_applied(&deriv);
+    }
+    report(Some(ApplyOutcome::Applied));
+    Round::Applied
+}
+
+fn health_checks_pass(checks: &[String]) -> bool {
+    for check in checks {
+        let ok = Command::new("sh")
+            .args(["-c", check])
+            .status()
+            .is_ok_and(|x| x.success());
+        if !ok {
+            println!("ERROR: {} {}", "health check failed:".red(), check);
+            return false;
+        }
+    }
+    true
+}
+
+fn rollback() {
+    println!("INFO: rolling back to the previous generation");
+    let rolled_back = run_privileged(
+        &[
+            "nix-env",
+            "--profile",
+            "/nix/var/nix/profiles/system",
+            "--rollback",
+        ],
+        None,
+    )
+    .is_some_and(|x| x.success())
+        && run_privileged(
+            &[
+                "/nix/var/nix/profiles/system/bin/switch-to-configuration",
+                "switch",
+            ],
+            None,
+        )
+        .is_some_and(|x| x.success());
+    if !rolled_back {
+        println!("ERROR: {}", "Failed to roll back!".red());
+    }
+}
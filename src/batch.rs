@@ -0,0 +1,285 @@
+use std::{fs, io::Read, path::Path};
+
+use colored::Colorize;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    copy_to_server, git, make_req, print_exit,
+    provenance::Provenance,
+    store_path::{self, StorePath},
+    UploadHashAPI,
+};
+
+/// An entry as given, before its path is checked.
+struct RawEntry {
+    name: Option<String>,
+    path: String,
+    branch: String,
+}
+
+struct Entry {
+    name: String,
+    store_hash: String,
+    branch: String,
+}
+
+/// The host name of a `nixos-system-<host>-<version>` toplevel.
+fn host_of(path: &StorePath) -> Option<String> {
+    let rest = path.name.strip_prefix("nixos-system-")?;
+    Some(rest.rsplit_once('-')?.0.to_owned())
+}
+
+/// One entry, either `{"name", "storeHash", "branch"}` or a result of `nix build --json`,
+/// which is named after the host of its toplevel.
+fn parse_entry(value: &Value, branch: &str) -> Result<RawEntry, String> {
+    let path = value["storeHash"]
+        .as_str()
+        .or(value["outputs"]["out"].as_str())
+        .ok_or(format!("no storeHash or outputs.out in {}", value))?;
+    Ok(RawEntry {
+        name: value["name"].as_str().map(|x| x.to_owned()),
+        path: path.to_owned(),
+        branch: value["branch"].as_str().unwrap_or(branch).to_owned(),
+    })
+}
+
+/// Entries from a JSON array, JSON lines or several `nix build --json` outputs one after another.
+fn parse_entries(text: &str, branch: &str) -> Result<Vec<RawEntry>, String> {
+    let mut entries = Vec::new();
+    for value in serde_json::Deserializer::from_str(text).into_iter::<Value>() {
+        let value = value.map_err(|x| format!("parsing the entries: {}", x))?;
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    entries.push(parse_entry(&value, branch)?);
+                }
+            }
+            value => entries.push(parse_entry(&value, branch)?),
+        }
+    }
+    Ok(entries)
+}
+
+/// Check every path like a single upload does, returning all the bad entries at once.
+fn check_entries(entries: Vec<RawEntry>, any_path: bool) -> Result<Vec<Entry>, Vec<String>> {
+    let mut checked = Vec::new();
+    let mut errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let result = store_path::resolve(&entry.path, any_path).and_then(|store_path| {
+            let name = match entry.name {
+                Some(x) => x,
+                None => host_of(&store_path).ok_or(format!("no name given for {}", store_path))?,
+            };
+            Ok(Entry {
+                name,
+                store_hash: store_path.to_string(),
+                branch: entry.branch,
+            })
+        });
+        match result {
+            Ok(x) => checked.push(x),
+            Err(x) => errors.push(format!("entry {}: {}", index + 1, x)),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(checked),
+        false => Err(errors),
+    }
+}
+
+/// `deriv up --from-file`, `-` reads stdin.
+pub fn handle_deriv_upload_batch(
+    file: &str,
+    branch: Option<String>,
+    force: Option<bool>,
+    message: Option<String>,
+    any_path: bool,
+) {
+    let mut text = String::new();
+    let read = if file == "-" {
+        std::io::stdin().read_to_string(&mut text).map(|_| ())
+    } else {
+        fs::read_to_string(file).map(|x| text = x)
+    };
+    if let Err(x) = read {
+        print_exit(&format!("ERROR: {}", format!("{}: {}", file, x).red()), 1);
+    }
+
+    let cwd = Path::new(".");
+    git::warn_unpublished(cwd);
+    let branch = branch
+        .or_else(|| git::upload_branch(cwd, &Config::load().branch_map))
+        .unwrap_or("main".to_owned());
+    let entries = match parse_entries(&text, &branch) {
+        Ok(x) if x.is_empty() => print_exit(&format!("ERROR: {}", "no entries given".red()), 1),
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+    let entries = match check_entries(entries, any_path) {
+        Ok(x) => x,
+        Err(errors) => {
+            for error in &errors {
+                println!("ERROR: {}", error.red());
+            }
+            print_exit(
+                &format!(
+                    "ERROR: {}",
+                    format!("{} bad entries, nothing was uploaded", errors.len()).red()
+                ),
+                1,
+            );
+        }
+    };
+
+    // Like a single upload, paths that aren't in the local store are left to the server
+    let (paths, missing): (Vec<&str>, Vec<&str>) = entries
+        .iter()
+        .map(|x| x.store_hash.as_str())
+        .partition(|x| store_path::is_valid(x));
+    for path in missing {
+        println!(
+            "WARN: {}",
+            format!(
+                "{} is not in the local store, not copying it anywhere",
+                path
+            )
+            .yellow()
+        );
+    }
+    if !paths.is_empty() {
+        println!("INFO: uploading {} derivation closures", paths.len());
+        if let Err(x) = copy_to_server(&paths) {
+            print_exit(&x, 1);
+        }
+    }
+
+    let provenance = Provenance::collect(None, message);
+    let payload: Vec<UploadHashAPI> = entries
+        .iter()
+        .map(|x| {
+            UploadHashAPI::new(
+                &x.name,
+                &x.store_hash,
+                x.branch.clone(),
+                force,
+                provenance.clone(),
+            )
+        })
+        .collect();
+    let json_payload =
+        serde_json::to_string(&payload).expect("Failed to serialize payload to json.");
+
+    // The server registers either all of the entries or none of them
+    match make_req("POST /derivations/batch", Some(&json_payload)) {
+        Ok(res) if res.status.success() => {
+            for entry in &entries {
+                println!(
+                    "\t{} ({}) {}",
+                    entry.name.green(),
+                    entry.branch,
+                    entry.store_hash
+                );
+            }
+            println!(
+                "{} (server response: {})",
+                format!("{} derivations uploaded succesffuly!", entries.len()).green(),
+                res.body
+            );
+        }
+        Ok(res) => print_exit(
+            &format!(
+                "ERROR: {}; {} {}\n\t{}",
+                "failed to upload derivations, none were added".red(),
+                res.status.status_code,
+                res.status.status_message,
+                res.body
+            ),
+            1,
+        ),
+        Err(err) => print_exit(
+            &format!(
+                "ERROR: {}",
+                format!("Failed to parse response body! err: {}", err).red()
+            ),
+            1,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEB: &str =
+        "/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-nixos-system-web-24.05.20240601.abcdef0";
+    const DB: &str = "/nix/store/9v5d40jyvmwgnq1nj8f19ji2rcc5dksb-nixos-system-db-1-24.05";
+
+    fn summary(entries: &[RawEntry]) -> Vec<(Option<&str>, &str, &str)> {
+        entries
+            .iter()
+            .map(|x| (x.name.as_deref(), x.path.as_str(), x.branch.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_json_array() {
+        let text = format!(
+            r#"[{{"name": "web", "storeHash": "{}"}}, {{"name": "db", "storeHash": "{}", "branch": "staging"}}]"#,
+            WEB, DB
+        );
+        let entries = parse_entries(&text, "main").unwrap();
+        assert_eq!(
+            summary(&entries),
+            [(Some("web"), WEB, "main"), (Some("db"), DB, "staging")]
+        );
+    }
+
+    #[test]
+    fn parse_json_lines() {
+        let text = format!(
+            "{{\"name\": \"web\", \"storeHash\": \"{}\"}}\n{{\"storeHash\": \"{}\"}}\n",
+            WEB, DB
+        );
+        let entries = parse_entries(&text, "main").unwrap();
+        assert_eq!(
+            summary(&entries),
+            [(Some("web"), WEB, "main"), (None, DB, "main")]
+        );
+    }
+
+    #[test]
+    fn parse_nix_build_outputs() {
+        // Two `nix build --json` runs one after another
+        let text = format!(
+            r#"[{{"drvPath": "/nix/store/x.drv", "outputs": {{"out": "{}"}}}}]
+[{{"drvPath": "/nix/store/y.drv", "outputs": {{"out": "{}"}}}}]"#,
+            WEB, DB
+        );
+        let entries = parse_entries(&text, "main").unwrap();
+        assert_eq!(summary(&entries), [(None, WEB, "main"), (None, DB, "main")]);
+    }
+
+    #[test]
+    fn parse_bad_entries() {
+        assert!(parse_entries(r#"[{"name": "web"}]"#, "main").is_err());
+        assert!(parse_entries("{\"storeHash\": ", "main").is_err());
+        assert!(parse_entries("", "main").unwrap().is_empty());
+    }
+
+    #[test]
+    fn host_of_toplevels() {
+        let host = |x: &str| host_of(&StorePath::parse(x).unwrap());
+        assert_eq!(host(WEB).as_deref(), Some("web"));
+        // Only the version after the last dash is dropped
+        assert_eq!(host(DB).as_deref(), Some("db-1"));
+        assert_eq!(
+            host("/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-hello-2.12.1"),
+            None
+        );
+        assert_eq!(
+            host("/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-nixos-system-web"),
+            None
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use colored::Colorize;
+
+use crate::{config::Config, git, print_exit, provenance::Provenance, upload_deriv};
+
+/// Names of all `nixosConfigurations` of the flake.
+fn configurations(flake: &str) -> Result<Vec<String>, String> {
+    let out = Command::new("nix")
+        .args([
+            "eval",
+            "--json",
+            &format!("{}#nixosConfigurations", flake),
+            "--apply",
+            "builtins.attrNames",
+        ])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|x| format!("could not run `nix eval`: {}", x))?;
+    if !out.status.success() {
+        return Err("`nix eval` of nixosConfigurations failed".to_owned());
+    }
+    serde_json::from_slice(&out.stdout)
+        .map_err(|x| format!("parsing the output of `nix eval`: {}", x))
+}
+
+/// Build the toplevel of every host in one `nix build`, returning their store paths in order.
+fn build_toplevels(
+    flake: &str,
+    hosts: &[String],
+    builders: Option<&str>,
+) -> Result<Vec<String>, String> {
+    let mut cmd = Command::new("nix");
+    cmd.args(["build", "--no-link", "--json"]);
+    if let Some(builders) = builders {
+        cmd.args(["--builders", builders]);
+    }
+    for host in hosts {
+        cmd.arg(format!(
+            "{}#nixosConfigurations.\"{}\".config.system.build.toplevel",
+            flake, host
+        ));
+    }
+    let out = cmd
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|x| format!("could not run `nix build`: {}", x))?;
+    if !out.status.success() {
+        return Err("`nix build` failed".to_owned());
+    }
+    let results: Vec<serde_json::Value> = serde_json::from_slice(&out.stdout)
+        .map_err(|x| format!("parsing the output of `nix build`: {}", x))?;
+    results
+        .iter()
+        .map(|x| {
+            x["outputs"]["out"]
+                .as_str()
+                .map(|x| x.to_owned())
+                .ok_or("`nix build` returned no `out` path".to_owned())
+        })
+        .collect()
+}
+
+/// Only local flakes have a git branch to take.
+fn flake_dir(flake: &str) -> Option<&Path> {
+    let path = flake
+        .strip_prefix("path:")
+        .or(flake.strip_prefix("git+file://"));
+    match path {
+        Some(x) => Some(Path::new(x)),
+        None if !flake.contains(':') => Some(Path::new(flake)),
+        None => None,
+    }
+}
+
+pub fn handle_deriv_build_up(
+    flake_ref: String,
+    all: bool,
+    branch: Option<String>,
+    builders: Option<String>,
+    force: Option<bool>,
+    message: Option<String>,
+) {
+    let (flake, host) = match flake_ref.split_once('#') {
+        Some((flake, host)) => (flake.to_owned(), Some(host.to_owned())),
+        None => (flake_ref, None),
+    };
+    let hosts = match (host, all) {
+        (Some(_), true) => print_exit(
+            &format!("ERROR: {}", "give either <flake>#<host> or --all".red()),
+            1,
+        ),
+        (Some(host), false) => vec![host],
+        (None, _) => match configurations(&flake) {
+            Ok(x) => x,
+            Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+        },
+    };
+    if let Some(dir) = flake_dir(&flake) {
+        git::warn_unpublished(dir);
+    }
+    let branch_map = Config::load().branch_map;
+    let branch = branch
+        .or_else(|| flake_dir(&flake).and_then(|dir| git::upload_branch(dir, &branch_map)))
+        .unwrap_or_else(|| {
+            println!("WARN: no git branch found for {}, using main", flake);
+            "main".to_owned()
+        });
+
+    println!(
+        "INFO: building {} on the branch {}",
+        hosts.join(", "),
+        branch.green()
+    );
+    let paths = match build_toplevels(&flake, &hosts, builders.as_deref()) {
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+
+    let provenance = Provenance::collect(flake_dir(&flake), message);
+    let mut failed = Vec::new();
+    for (host, path) in hosts.iter().zip(paths) {
+        println!("INFO: uploading {} as {}", path, host.green());
+        match upload_deriv(host, &path, branch.clone(), force, provenance.clone()) {
+            Ok(x) => println!("{}", x),
+            Err(x) => {
+                println!("{}", x);
+                failed.push(host.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        print_exit(
+            &format!("ERROR: {} {}", "failed to upload".red(), failed.join(", ")),
+            1,
+        );
+    }
+}
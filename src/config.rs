@@ -0,0 +1,289 @@
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use colored::Colorize;
+use serde_derive::{Deserialize, Serialize};
+
+/// Settings read from `$GURL_CONFIG`, `~/.config/gurl/config.json` or `/etc/gurl/config.json`,
+/// whichever exists first. Everything is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub agent: AgentConfig,
+    /// Git branch -> server branch, for uploads that take the branch from git.
+    pub branch_map: HashMap<String, String>,
+    /// Where `deriv up` copies closures to, every one of them gets a copy.
+    pub copy_destinations: Vec<CopyDestination>,
+    /// Stores `deriv apply` copies closures from(binary caches, ssh stores, LAN peers).
+    /// The fastest one that has the closure is used, the rest are fallbacks.
+    pub substituters: Vec<String>,
+    /// `<name>:<base64>` ed25519 keys, like nix's `trusted-public-keys`. Systems have to be
+    /// signed by one of them to be applied; nothing is checked when empty.
+    pub trusted_keys: Vec<String>,
+    /// Secret key(`nix key generate-secret`) `deriv up` signs the entries with.
+    pub signing_key: Option<PathBuf>,
+    /// Keys allowed to publish entries, `deriv apply` refuses entries not signed by one of
+    /// them, entries older than the one the agent applied last and, in the agent, pins.
+    /// Nothing is checked when empty.
+    pub publishers: Vec<Publisher>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            agent: AgentConfig::default(),
+            branch_map: HashMap::from([("master".to_owned(), "main".to_owned())]),
+            copy_destinations: vec![CopyDestination::One(
+                "ssh://root@elaina.tami.moe".to_owned(),
+            )],
+            substituters: vec![
+                "https://nix-cache.tami.moe".to_owned(),
+                "ssh://root@elaina.tami.moe".to_owned(),
+            ],
+            trusted_keys: Vec::new(),
+            signing_key: None,
+            publishers: Vec::new(),
+        }
+    }
+}
+
+/// A store URI for `nix copy --to`, like `ssh-ng://root@elaina.tami.moe`,
+/// `s3://cache?endpoint=minio.lan:9000&scheme=http` or `file:///srv/cache`.
+/// A list is one destination reachable in several ways, tried in order until one works.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CopyDestination {
+    One(String),
+    FailOver(Vec<String>),
+}
+
+impl CopyDestination {
+    pub fn uris(&self) -> &[String] {
+        match self {
+            CopyDestination::One(x) => std::slice::from_ref(x),
+            CopyDestination::FailOver(x) => x,
+        }
+    }
+}
+
+/// `{"key": "<name>:<base64>", "branches": ["main"]}`, no branches means any branch.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Publisher {
+    pub key: String,
+    #[serde(default)]
+    pub branches: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Name of this host on the server, `$HOSTNAME` when unset.
+    pub name: Option<String>,
+    pub branch: String,
+    /// Seconds between two polls of the server.
+    pub poll_interval: u64,
+    /// Only switch inside one of these; always allowed when empty.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Shell commands that must all exit with 0 after a switch.
+    pub health_checks: Vec<String>,
+    /// Go back to the previous generation if a health check fails. A failed activation is
+    /// always rolled back, by `gurl-apply-helper` itself.
+    pub rollback_on_failure: bool,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            name: None,
+            branch: "main".to_owned(),
+            poll_interval: 300,
+            maintenance_windows: Vec::new(),
+            health_checks: Vec::new(),
+            rollback_on_failure: true,
+        }
+    }
+}
+
+/// A daily time range like `{"days": ["Sat", "Sun"], "start": "22:00", "end": "05:00"}`.
+/// A range whose end is before its start spans midnight.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaintenanceWindow {
+    /// Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: String,
+    pub end: String,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, now: DateTime<Local>) -> bool {
+        let parse = |x: &str| NaiveTime::parse_from_str(x, "%H:%M");
+        let (start, end) = match (parse(&self.start), parse(&self.end)) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => {
+                println!(
+                    "WARN: {}",
+                    format!(
+                        "invalid maintenance window {}-{}, expected HH:MM",
+                        self.start, self.end
+                    )
+                    .yellow()
+                );
+                return false;
+            }
+        };
+        let time = now.time();
+        let day_allowed = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if start <= end {
+            day_allowed(now.weekday()) && start <= time && time < end
+        } else if time >= start {
+            day_allowed(now.weekday())
+        } else {
+            // Past midnight, so the window started yesterday
+            time < end && day_allowed(now.weekday().pred())
+        }
+    }
+}
+
+/// Parses `22:00-05:00` or `Sat,Sun@22:00-05:00`.
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (days, range) = match str.split_once('@') {
+            Some((days, range)) => (days, range),
+            None => ("", str),
+        };
+        let days = days
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.parse::<Weekday>()
+                    .map_err(|_| format!("invalid day \"{}\" in window \"{}\"", x, str))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (start, end) = range.split_once('-').ok_or(format!(
+            "window \"{}\" should look like [Sat,Sun@]HH:MM-HH:MM",
+            str
+        ))?;
+        for time in [start, end] {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("invalid time \"{}\" in window \"{}\"", time, str))?;
+        }
+        Ok(MaintenanceWindow {
+            days,
+            start: start.to_owned(),
+            end: end.to_owned(),
+        })
+    }
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        if let Ok(path) = std::env::var("GURL_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let user_config = std::env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| std::env::var("HOME").map(|x| PathBuf::from(x).join(".config")))
+            .map(|x| x.join("gurl/config.json"));
+        [
+            user_config.ok(),
+            Some(PathBuf::from("/etc/gurl/config.json")),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|x| x.exists())
+    }
+
+    pub fn load() -> Config {
+        let path = match Config::path() {
+            Some(x) => x,
+            None => return Config::default(),
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(x) => {
+                println!(
+                    "ERROR: {}",
+                    format!("reading config {}: {}", path.display(), x).red()
+                );
+                std::process::exit(1);
+            }
+        };
+        match serde_json::from_str(&contents) {
+            Ok(x) => x,
+            Err(x) => {
+                println!(
+                    "ERROR: {}",
+                    format!("parsing config {}: {}", path.display(), x).red()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// 2026-06-13 is a Saturday.
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 6, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_windows() {
+        let window: MaintenanceWindow = "Sat,Sun@22:00-05:00".parse().unwrap();
+        assert_eq!(window.days, [Weekday::Sat, Weekday::Sun]);
+        assert_eq!(
+            (window.start.as_str(), window.end.as_str()),
+            ("22:00", "05:00")
+        );
+
+        let window: MaintenanceWindow = "01:30-02:00".parse().unwrap();
+        assert!(window.days.is_empty());
+        assert_eq!(window.start, "01:30");
+    }
+
+    #[test]
+    fn parse_invalid_windows() {
+        for window in [
+            "22:00",
+            "Sat@22:00",
+            "Caturday@22:00-05:00",
+            "25:00-05:00",
+            "22:00-5",
+        ] {
+            assert!(window.parse::<MaintenanceWindow>().is_err(), "{}", window);
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window: MaintenanceWindow = "01:30-02:00".parse().unwrap();
+        assert!(window.contains(at(13, 1, 30)));
+        assert!(window.contains(at(15, 1, 59)));
+        // The end is excluded
+        assert!(!window.contains(at(13, 2, 0)));
+        assert!(!window.contains(at(13, 1, 29)));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let window: MaintenanceWindow = "Sat@22:00-05:00".parse().unwrap();
+        assert!(window.contains(at(13, 22, 0)));
+        assert!(window.contains(at(13, 23, 59)));
+        // Sunday morning is still in the window that started on Saturday
+        assert!(window.contains(at(14, 4, 59)));
+        assert!(!window.contains(at(14, 5, 0)));
+        assert!(!window.contains(at(14, 22, 0)));
+        // Saturday morning belongs to Friday night, which isn't allowed
+        assert!(!window.contains(at(13, 3, 0)));
+        assert!(!window.contains(at(13, 12, 0)));
+    }
+}
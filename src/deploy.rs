@@ -0,0 +1,449 @@
+use std::{
+    fs::{DirBuilder, File},
+    io::{IsTerminal, Write},
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use colored::{ColoredString, Colorize};
+
+use crate::{
+    group::{self, resolve_entry},
+    policy::{self, Policy},
+    print_exit,
+    ssh_agent::SshAgent,
+    ssh_agent_from_env, table_print, Fonal, DB,
+};
+
+/// Same steps as `gurl-apply-helper`, for machines that might not have it installed.
+const ACTIVATE_SCRIPT: &str =
+    "nix-env --profile /nix/var/nix/profiles/system --set \"$0\" || exit 1; \
+/nix/var/nix/profiles/system/bin/switch-to-configuration switch \
+|| { echo \"Something went wrong... rolling back?\"; \
+nix-env --profile /nix/var/nix/profiles/system --rollback; \
+/nix/var/nix/profiles/system/bin/switch-to-configuration switch; exit 1; }";
+
+#[derive(Clone)]
+pub enum Phase {
+    Waiting,
+    Copying,
+    Activating,
+    Done,
+    Failed(String),
+}
+
+impl Phase {
+    fn colored(&self) -> ColoredString {
+        match self {
+            Phase::Waiting => "waiting".bright_black(),
+            Phase::Copying => "copying".yellow(),
+            Phase::Activating => "activating".bright_yellow(),
+            Phase::Done => "done".green(),
+            Phase::Failed(_) => "failed".red(),
+        }
+    }
+}
+
+pub struct Target {
+    /// Name of the host on the server.
+    pub name: String,
+    /// Where to ssh to.
+    pub address: String,
+    pub user: String,
+}
+
+impl Target {
+    pub fn new(name: String, address: Option<String>, user: Option<String>) -> Target {
+        Target {
+            address: address.unwrap_or(name.clone()),
+            user: user
+                .or(std::env::var("USER").ok())
+                .unwrap_or("root".to_owned()),
+            name,
+        }
+    }
+
+    fn ssh_host(&self) -> String {
+        format!("{}@{}", self.user, self.address)
+    }
+
+    pub fn needs_sudo(&self) -> bool {
+        self.user != "root"
+    }
+}
+
+/// Ask for the sudo password of the targets once, if any of them needs it.
+pub fn remote_password(targets: &[Target]) -> Option<String> {
+    if !targets.iter().any(|x| x.needs_sudo()) {
+        return None;
+    }
+    Some(
+        rpassword::prompt_password("[sudo] password for the remote hosts: ")
+            .expect("Failed to read the password"),
+    )
+}
+
+fn output(log: Option<&File>) -> Stdio {
+    match log.and_then(|x| x.try_clone().ok()) {
+        Some(file) => Stdio::from(file),
+        None => Stdio::inherit(),
+    }
+}
+
+/// Copy the closure of `hash` to `target` and switch it there.
+/// The output goes to `log` if given, otherwise it is streamed to the terminal.
+pub fn deploy(
+    target: &Target,
+    hash: &str,
+    password: Option<&str>,
+    agent: Option<&SshAgent>,
+    log: Option<&File>,
+    on_phase: &dyn Fn(Phase),
+) -> Result<(), String> {
+    if !hash.starts_with("/nix/store/") || hash.contains(['\'', '"', ' ']) {
+        return Err(format!("{} is not a store path", hash));
+    }
+
+    on_phase(Phase::Copying);
+    let mut nix_copy = Command::new("nix");
+    nix_copy
+        .args([
+            "copy",
+            "--to",
+            &format!("ssh://{}", target.ssh_host()),
+            hash,
+        ])
+        .stdout(output(log))
+        .stderr(output(log));
+    if let Some(agent) = agent {
+        agent.prepare(&mut nix_copy);
+    }
+    let copied = nix_copy
+        .status()
+        .map_err(|x| format!("could not run `nix copy`: {}", x))?;
+    if !copied.success() {
+        return Err("`nix copy` to the host failed".to_owned());
+    }
+
+    on_phase(Phase::Activating);
+    let activate = match target.needs_sudo() {
+        true => format!("sudo -S -p '' sh -c '{}' '{}'", ACTIVATE_SCRIPT, hash),
+        false => format!("sh -c '{}' '{}'", ACTIVATE_SCRIPT, hash),
+    };
+    let mut ssh = Command::new("ssh");
+    if let Some(agent) = agent {
+        agent.prepare(&mut ssh).args(agent.ssh_opts());
+    }
+    let mut child = ssh
+        .arg(target.ssh_host())
+        .arg(activate)
+        .stdin(Stdio::piped())
+        .stdout(output(log))
+        .stderr(output(log))
+        .spawn()
+        .map_err(|x| format!("could not run `ssh`: {}", x))?;
+    let mut stdin = child.stdin.take().expect("Failed to open stdin");
+    if target.needs_sudo() {
+        let password = format!("{}\n", password.unwrap_or_default());
+        std::thread::spawn(move || stdin.write_all(password.as_bytes()));
+    } else {
+        drop(stdin);
+    }
+    let status = child
+        .wait()
+        .map_err(|x| format!("waiting for `ssh`: {}", x))?;
+    if !status.success() {
+        return Err("activation on the host failed".to_owned());
+    }
+    Ok(())
+}
+
+/// Why `host` shouldn't be switched now, if anything. The policy not being there counts.
+fn blocker(host: &str) -> Option<String> {
+    match Policy::get(host) {
+        Ok(x) => x.blocker(Local::now()),
+        Err(x) => Some(format!("could not check the policy of {}: {}", host, x)),
+    }
+}
+
+fn deploy_one(
+    host: String,
+    branch: String,
+    address: Option<String>,
+    user: Option<String>,
+    ignore_hold: bool,
+) {
+    let target = Target::new(host, address, user);
+    if let Some(reason) = blocker(&target.name) {
+        println!("WARN: {}", reason.yellow());
+        if !ignore_hold {
+            print_exit(
+                &format!(
+                    "ERROR: {}",
+                    "refusing to deploy, use --ignore-hold to override".red()
+                ),
+                1,
+            );
+        }
+        if !policy::confirm_ignore(&target.name) {
+            print_exit("ERROR: aborted", 1);
+        }
+    }
+    let deriv = match resolve_entry(&target.name, &branch) {
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+
+    println!("INFO: this will be deployed to {}:", target.ssh_host());
+    println!("\tname: {}", deriv.name);
+    println!("\tbranch: {}", deriv.branch);
+    println!("\thash: {}", deriv.storeHash);
+
+    let password = remote_password(std::slice::from_ref(&target));
+    let agent = ssh_agent_from_env();
+    match deploy(
+        &target,
+        &deriv.storeHash,
+        password.as_deref(),
+        agent.as_ref(),
+        None,
+        &|_| {},
+    ) {
+        Ok(()) => println!(
+            "INFO: {}",
+            format!("Successfully deployed to {}!", target.name).green()
+        ),
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    }
+}
+
+/// Where the logs of a deploy to many hosts go, `~/.cache/gurl/deploy`. Only the user
+/// can get in, unlike a shared temp dir where someone could plant a symlink.
+fn log_dir() -> Result<PathBuf, String> {
+    let dir = std::env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|x| PathBuf::from(x).join(".cache")))
+        .map_err(|_| "neither $XDG_CACHE_HOME nor $HOME is set".to_owned())?
+        .join("gurl/deploy");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|x| format!("{}: {}", dir.display(), x))?;
+    Ok(dir)
+}
+
+struct Progress {
+    name: String,
+    phase: Phase,
+    started: Option<Instant>,
+    log: String,
+}
+
+fn render(progress: &[Progress]) {
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for host in progress {
+        let elapsed = match host.started {
+            Some(started) => format!("{}s", started.elapsed().as_secs()),
+            None => "---".to_owned(),
+        };
+        let detail = match &host.phase {
+            Phase::Failed(x) => x.clone(),
+            _ => host.log.clone(),
+        };
+        table.push(vec![
+            host.name.clone().into(),
+            host.phase.colored().into(),
+            elapsed.into(),
+            detail.into(),
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Host".to_owned()),
+        Fonal::String("Phase".to_owned()),
+        Fonal::String("Time".to_owned()),
+        Fonal::String("Log".to_owned()),
+    ]);
+    table_print::<4>(table);
+}
+
+fn deploy_many(
+    names: Vec<String>,
+    branch: String,
+    user: Option<String>,
+    jobs: usize,
+    ignore_hold: bool,
+) {
+    let names: Vec<String> = names
+        .into_iter()
+        .filter(|host| {
+            let reason = match blocker(host) {
+                Some(x) => x,
+                None => return true,
+            };
+            if !ignore_hold {
+                println!("WARN: {}, skipping it", reason.yellow());
+                return false;
+            }
+            println!("WARN: {}", reason.yellow());
+            let confirmed = policy::confirm_ignore(host);
+            if !confirmed {
+                println!("INFO: skipping {}", host);
+            }
+            confirmed
+        })
+        .collect();
+    if names.is_empty() {
+        print_exit(&format!("ERROR: {}", "No hosts to deploy to".red()), 1);
+    }
+    let targets: Vec<Target> = names
+        .into_iter()
+        .map(|x| Target::new(x, None, user.clone()))
+        .collect();
+    let password = remote_password(&targets);
+    let agent = ssh_agent_from_env();
+
+    let log_dir = log_dir().unwrap_or_else(|x| {
+        print_exit(
+            &format!("ERROR: {}", format!("no place for the logs, {}", x).red()),
+            1,
+        )
+    });
+    let progress = Mutex::new(
+        targets
+            .iter()
+            .map(|x| Progress {
+                name: x.name.clone(),
+                phase: Phase::Waiting,
+                started: None,
+                log: log_dir
+                    .join(format!("{}.log", x.name))
+                    .display()
+                    .to_string(),
+            })
+            .collect::<Vec<_>>(),
+    );
+    let queue = Mutex::new((0..targets.len()).collect::<Vec<_>>());
+    let live = std::io::stdout().is_terminal();
+
+    let set_phase = |index: usize, phase: Phase| {
+        let mut progress = progress.lock().unwrap();
+        if !live {
+            println!("{}: {}", progress[index].name, phase.colored());
+        }
+        if matches!(phase, Phase::Copying) {
+            progress[index].started = Some(Instant::now());
+        }
+        progress[index].phase = phase;
+    };
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| loop {
+                    let index = match queue.lock().unwrap().pop() {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    let target = &targets[index];
+                    let result = resolve_entry(&target.name, &branch).and_then(|deriv| {
+                        let log_path = progress.lock().unwrap()[index].log.clone();
+                        let log = File::create(&log_path)
+                            .map_err(|x| format!("creating {}: {}", log_path, x))?;
+                        deploy(
+                            target,
+                            &deriv.storeHash,
+                            password.as_deref(),
+                            agent.as_ref(),
+                            Some(&log),
+                            &|phase| set_phase(index, phase),
+                        )
+                    });
+                    match result {
+                        Ok(()) => set_phase(index, Phase::Done),
+                        Err(x) => set_phase(index, Phase::Failed(x)),
+                    }
+                })
+            })
+            .collect();
+
+        if live {
+            let lines = targets.len() + 2;
+            render(&progress.lock().unwrap());
+            while !workers.iter().all(|x| x.is_finished()) {
+                thread::sleep(Duration::from_millis(500));
+                // Move back up over the previous table and draw it again
+                print!("\x1b[{}A\x1b[J", lines);
+                render(&progress.lock().unwrap());
+            }
+        }
+    });
+
+    let progress = progress.into_inner().unwrap();
+    let failed: Vec<&Progress> = progress
+        .iter()
+        .filter(|x| matches!(x.phase, Phase::Failed(_)))
+        .collect();
+    println!(
+        "INFO: {} succeeded, {} failed",
+        (progress.len() - failed.len()).to_string().green(),
+        failed.len().to_string().red()
+    );
+    for host in &failed {
+        if let Phase::Failed(x) = &host.phase {
+            println!("ERROR: {}: {} (log: {})", host.name.red(), x, host.log);
+        }
+    }
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_deriv_deploy(
+    host: Option<String>,
+    all: bool,
+    group: Option<String>,
+    branch: String,
+    address: Option<String>,
+    user: Option<String>,
+    jobs: usize,
+    ignore_hold: bool,
+) {
+    if let Some(group) = group {
+        let members = group::members(&group)
+            .unwrap_or_else(|| print_exit(&format!("ERROR: no group named {}", group.red()), 1));
+        return deploy_many(members, branch, user, jobs, ignore_hold);
+    }
+    if all {
+        let groups = DB::get_groups().unwrap_or_else(|| {
+            print_exit(&format!("ERROR: {}", "Failed to get host groups".red()), 1)
+        });
+        let derivations = DB::get_all()
+            .unwrap_or_else(|| print_exit(&format!("ERROR: {}", "Failed to get entries".red()), 1));
+        // A group entry stands for its members, each resolved to its own entry later
+        let mut names: Vec<String> = derivations
+            .into_iter()
+            .filter(|x| x.branch == branch)
+            .flat_map(|x| match groups.iter().find(|group| group.name == x.name) {
+                Some(group) => group.hosts.clone(),
+                None => vec![x.name],
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        return deploy_many(names, branch, user, jobs, ignore_hold);
+    }
+    match host {
+        Some(host) => deploy_one(host, branch, address, user, ignore_hold),
+        None => print_exit(
+            &format!("ERROR: {}", "give a host, --all or --group".red()),
+            1,
+        ),
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::{DateTime, Duration, Local};
+use colored::{ColoredString, Colorize};
+use serde_derive::Serialize;
+
+use crate::{
+    group::{self, Group},
+    handle_date_to_dynamic_info, json_print, print_exit, table_print, Deriv, Fonal, OutputFormat,
+    Report, DB,
+};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostState {
+    UpToDate,
+    /// The host is still running something older than its branch.
+    Behind,
+    /// The host switched to something else after its branch was updated.
+    Diverged,
+    /// No report in a while(or ever).
+    Silent,
+}
+
+impl HostState {
+    fn colored(&self) -> ColoredString {
+        match self {
+            HostState::UpToDate => "up to date".green(),
+            HostState::Behind => "behind".yellow(),
+            HostState::Diverged => "diverged".red(),
+            HostState::Silent => "silent".bright_black(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HostStatus {
+    pub name: String,
+    /// The entry the host follows, its own name or one of its groups.
+    pub entry: String,
+    pub branch: String,
+    pub expected: String,
+    pub running: Option<String>,
+    pub last_seen: Option<DateTime<Local>>,
+    pub state: HostState,
+    pub reboot_required: bool,
+}
+
+/// Pick the entry a host should be running, its own or its group's(see
+/// `group::resolve_entry`): on the branch it last reported, otherwise `main`, otherwise
+/// any branch with an entry for it.
+fn expected_entry<'a>(
+    derivations: &'a [Deriv],
+    groups: &[Group],
+    host: &str,
+    report: Option<&Report>,
+) -> Option<&'a Deriv> {
+    let mut branches: Vec<&str> = report.map(|x| x.branch.as_str()).into_iter().collect();
+    branches.push("main");
+    branches.extend(derivations.iter().map(|x| x.branch.as_str()));
+    branches
+        .into_iter()
+        .find_map(|branch| group::entry_of(derivations, groups, host, branch))
+}
+
+/// Hostname that sent `report`, older clients only sent the entry name.
+fn report_host(report: &Report) -> &str {
+    if report.host.is_empty() {
+        &report.name
+    } else {
+        &report.host
+    }
+}
+
+fn host_state(expected: &Deriv, report: Option<&Report>, silent_after: Duration) -> HostState {
+    let report = match report {
+        Some(x) => x,
+        None => return HostState::Silent,
+    };
+    match report.date_reported {
+        Some(date) if Local::now() - date <= silent_after => {}
+        _ => return HostState::Silent,
+    }
+    if report.storeHash == expected.storeHash {
+        return HostState::UpToDate;
+    }
+    match (report.date_switched, expected.date_added) {
+        (Some(switched), Some(added)) if switched > added => HostState::Diverged,
+        _ => HostState::Behind,
+    }
+}
+
+/// Every host that reported, is in a group or has an entry of its own, with the state of
+/// the entry it follows.
+pub fn fleet_status(
+    derivations: &[Deriv],
+    groups: &[Group],
+    reports: &[Report],
+    silent_after: Duration,
+) -> Vec<HostStatus> {
+    let mut hosts: Vec<&str> = reports.iter().map(report_host).collect();
+    hosts.extend(
+        groups
+            .iter()
+            .flat_map(|x| x.hosts.iter().map(|x| x.as_str())),
+    );
+    hosts.extend(
+        derivations
+            .iter()
+            .map(|x| x.name.as_str())
+            .filter(|name| !groups.iter().any(|x| x.name == *name)),
+    );
+    hosts.sort();
+    hosts.dedup();
+
+    hosts
+        .into_iter()
+        .filter_map(|host| {
+            let report = reports
+                .iter()
+                .filter(|x| report_host(x) == host)
+                .max_by_key(|x| x.date_reported);
+            let expected = expected_entry(derivations, groups, host, report)?;
+            Some(HostStatus {
+                name: host.to_owned(),
+                entry: expected.name.clone(),
+                branch: expected.branch.clone(),
+                expected: expected.storeHash.clone(),
+                running: report.map(|x| x.storeHash.clone()),
+                last_seen: report.and_then(|x| x.date_reported),
+                state: host_state(expected, report, silent_after),
+                reboot_required: report.is_some_and(|x| x.reboot_required),
+            })
+        })
+        .collect()
+}
+
+pub fn handle_fleet_status(output: OutputFormat, silent_after: i64) {
+    let derivations = match DB::get_all() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get derivations".red()), 1),
+    };
+    let reports = DB::get_reports().unwrap_or_else(|| {
+        println!(
+            "WARN: {}",
+            "Failed to get host reports, every host is silent".yellow()
+        );
+        Vec::new()
+    });
+    let groups = match DB::get_groups() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get host groups".red()), 1),
+    };
+    let hosts = fleet_status(
+        &derivations,
+        &groups,
+        &reports,
+        Duration::hours(silent_after),
+    );
+
+    if output == OutputFormat::Json {
+        return json_print(&hosts);
+    }
+
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for host in hosts {
+        let running = match host.running {
+            Some(x) if x == host.expected => "(same)".to_owned().into(),
+            Some(x) => x.into(),
+            None => "---".to_owned().into(),
+        };
+        let name = if host.entry == host.name {
+            host.name
+        } else {
+            format!("{} ({})", host.name, host.entry)
+        };
+        table.push(vec![
+            name.into(),
+            host.branch.into(),
+            host.state.colored().into(),
+            if host.reboot_required {
+                "yes".yellow()
+            } else {
+                "".normal()
+            }
+            .into(),
+            handle_date_to_dynamic_info(host.last_seen).into(),
+            host.expected.into(),
+            running,
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Name".to_owned()),
+        Fonal::String("Branch".to_owned()),
+        Fonal::String("State".to_owned()),
+        Fonal::String("Reboot".to_owned()),
+        Fonal::String("Last Seen".to_owned()),
+        Fonal::String("Expected".to_owned()),
+        Fonal::String("Running".to_owned()),
+    ]);
+
+    table_print::<7>(table);
+}
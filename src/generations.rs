@@ -0,0 +1,254 @@
+use std::{fs, path::PathBuf};
+
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use serde_derive::Serialize;
+
+use crate::{
+    group::Group, handle_date_to_dynamic_info, json_print, narinfo::human_size,
+    preflight::free_space, print_exit, resolve_name, run_privileged, sudo_password_getter,
+    table_print, Deriv, Fonal, OutputFormat, DB,
+};
+
+const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// One `system-<number>-link` of the system profile.
+#[derive(Serialize, Debug, Clone)]
+pub struct Generation {
+    pub number: u32,
+    pub path: String,
+    pub date: Option<DateTime<Local>>,
+}
+
+/// All generations of the system profile, oldest first.
+pub fn list() -> Vec<Generation> {
+    let entries = match fs::read_dir(PROFILES_DIR) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    let mut generations: Vec<Generation> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let number = file_name
+                .strip_prefix("system-")?
+                .strip_suffix("-link")?
+                .parse()
+                .ok()?;
+            Some(Generation {
+                number,
+                path: fs::read_link(entry.path())
+                    .ok()?
+                    .to_string_lossy()
+                    .into_owned(),
+                date: entry
+                    .path()
+                    .symlink_metadata()
+                    .and_then(|x| x.modified())
+                    .ok()
+                    .map(DateTime::<Local>::from),
+            })
+        })
+        .collect();
+    generations.sort_by_key(|x| x.number);
+    generations
+}
+
+/// The generation the system profile points to.
+pub fn current() -> Option<u32> {
+    let link = fs::read_link(PathBuf::from(PROFILES_DIR).join("system")).ok()?;
+    link.to_string_lossy()
+        .strip_prefix("system-")?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+#[derive(Serialize)]
+struct GenerationInfo {
+    #[serde(flatten)]
+    generation: Generation,
+    current: bool,
+    booted: bool,
+    /// `name (branch)` of the server entries with the same store path.
+    entries: Vec<String>,
+}
+
+pub fn handle_deriv_generations(output: OutputFormat) {
+    let derivations: Vec<Deriv> = DB::get_all().unwrap_or_else(|| {
+        println!(
+            "WARN: {}",
+            "Failed to get derivations, not matching generations to entries".yellow()
+        );
+        Vec::new()
+    });
+    let current = current();
+    let booted = fs::read_link("/run/booted-system")
+        .ok()
+        .map(|x| x.to_string_lossy().into_owned());
+    let mut infos: Vec<GenerationInfo> = list()
+        .into_iter()
+        .map(|generation| GenerationInfo {
+            current: Some(generation.number) == current,
+            booted: booted.as_ref() == Some(&generation.path),
+            entries: derivations
+                .iter()
+                .filter(|x| x.storeHash == generation.path)
+                .map(|x| format!("{} ({})", x.name, x.branch))
+                .collect(),
+            generation,
+        })
+        .collect();
+    infos.reverse();
+    if output == OutputFormat::Json {
+        return json_print(&infos);
+    }
+    if infos.is_empty() {
+        return println!("INFO: the system profile has no generations");
+    }
+
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for info in infos {
+        let marker = match (info.current, info.booted) {
+            (true, true) => "current, booted".green(),
+            (true, false) => "current".green(),
+            (false, true) => "booted".normal(),
+            (false, false) => "".normal(),
+        };
+        table.push(vec![
+            info.generation.number.to_string().into(),
+            marker.into(),
+            handle_date_to_dynamic_info(info.generation.date).into(),
+            if info.entries.is_empty() {
+                "---".to_owned()
+            } else {
+                info.entries.join(", ")
+            }
+            .into(),
+            info.generation.path.into(),
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Gen".to_owned()),
+        Fonal::String("".to_owned()),
+        Fonal::String("Date".to_owned()),
+        Fonal::String("Entries".to_owned()),
+        Fonal::String("Hash".to_owned()),
+    ]);
+
+    table_print::<5>(table);
+}
+
+/// Whether the server knows `generation` as an entry of `host`, or of a group it's in.
+fn is_entry_of(
+    generation: &Generation,
+    host: &str,
+    derivations: &[Deriv],
+    groups: &[Group],
+) -> bool {
+    derivations.iter().any(|x| {
+        x.storeHash == generation.path
+            && (x.name == host
+                || groups
+                    .iter()
+                    .any(|g| g.name == x.name && g.hosts.iter().any(|h| h == host)))
+    })
+}
+
+/// Delete old generations of the system profile, keeping the newest `keep`, the current and
+/// booted ones and those matching a server entry of `host`.
+pub fn handle_deriv_gc_local(host: String, keep: usize, gc: bool, dry_run: bool) {
+    let host = resolve_name(host);
+    let derivations = match DB::get_all() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get derivations".red()), 1),
+    };
+    let groups = match DB::get_groups() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get host groups".red()), 1),
+    };
+    let current = current();
+    let booted = fs::read_link("/run/booted-system")
+        .ok()
+        .map(|x| x.to_string_lossy().into_owned());
+
+    let generations = list();
+    // Number of the oldest generation that is kept for being among the newest
+    let oldest_kept = generations
+        .get(generations.len().saturating_sub(keep))
+        .map_or(u32::MAX, |x| x.number);
+    let (kept, deleted): (Vec<&Generation>, Vec<&Generation>) =
+        generations.iter().partition(|generation| {
+            generation.number >= oldest_kept
+                || Some(generation.number) == current
+                || booted.as_ref() == Some(&generation.path)
+                || is_entry_of(generation, &host, &derivations, &groups)
+        });
+    let numbers = |x: &[&Generation]| {
+        x.iter()
+            .map(|x| x.number.to_string())
+            .collect::<Vec<String>>()
+    };
+    println!("INFO: keeping generations {}", numbers(&kept).join(", "));
+    if deleted.is_empty() {
+        return println!("INFO: no generations to delete");
+    }
+    println!(
+        "INFO: deleting generations {}",
+        numbers(&deleted).join(", ").yellow()
+    );
+    if dry_run {
+        return;
+    }
+    let free_before = free_space();
+
+    let password = sudo_password_getter().expect("Failed to get sudo password");
+    let deleted = numbers(&deleted);
+    let mut args = vec![
+        "nix-env",
+        "--profile",
+        "/nix/var/nix/profiles/system",
+        "--delete-generations",
+    ];
+    args.extend(deleted.iter().map(|x| x.as_str()));
+    if !run_privileged(&args, Some(password.clone())).is_some_and(|x| x.success()) {
+        print_exit(
+            &format!("ERROR: {}", "Failed to delete the generations".red()),
+            1,
+        );
+    }
+    // Drops the boot entries of the deleted generations, freeing /boot
+    let boot = run_privileged(
+        &[
+            "/nix/var/nix/profiles/system/bin/switch-to-configuration",
+            "boot",
+        ],
+        Some(password.clone()),
+    );
+    if !boot.is_some_and(|x| x.success()) {
+        println!(
+            "WARN: {}",
+            "Failed to update the boot entries, /boot still has the old ones".yellow()
+        );
+    }
+
+    if !gc {
+        // The deleted generations stay in the store until the next garbage collection
+        println!(
+            "INFO: {} run again with --gc(or `nix-store --gc`) to free their space on /nix",
+            "Deleted the generations,".green()
+        );
+        return;
+    }
+    println!("INFO: collecting garbage");
+    if !run_privileged(&["nix-store", "--gc"], Some(password)).is_some_and(|x| x.success()) {
+        print_exit(&format!("ERROR: {}", "`nix-store --gc` failed".red()), 1);
+    }
+    match (free_before, free_space()) {
+        (Some(before), Some(after)) => println!(
+            "INFO: {} freed on /nix",
+            human_size(after.saturating_sub(before)).green()
+        ),
+        _ => println!("INFO: {}", "Deleted the generations".green()),
+    }
+}
@@ -0,0 +1,64 @@
+use std::{collections::HashMap, path::Path, process::Command};
+
+use colored::Colorize;
+
+/// Run `git` in `dir`, returning its trimmed stdout if it succeeded.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(out.stdout.trim_ascii()).into_owned())
+}
+
+/// The checked out branch in `dir`, `None` outside a repository or on a detached HEAD.
+pub fn current_branch(dir: &Path) -> Option<String> {
+    git(dir, &["symbolic-ref", "--short", "-q", "HEAD"])
+}
+
+pub fn revision(dir: &Path) -> Option<String> {
+    git(dir, &["rev-parse", "HEAD"])
+}
+
+/// Whether the working tree has uncommitted changes(untracked files included).
+pub fn is_dirty(dir: &Path) -> Option<bool> {
+    git(dir, &["status", "--porcelain"]).map(|x| !x.is_empty())
+}
+
+pub fn config(dir: &Path, key: &str) -> Option<String> {
+    git(dir, &["config", "--get", key]).filter(|x| !x.is_empty())
+}
+
+/// Commits on HEAD that its upstream doesn't have, `None` without an upstream.
+pub fn unpushed(dir: &Path) -> Option<usize> {
+    git(dir, &["rev-list", "--count", "@{upstream}..HEAD"]).and_then(|x| x.parse().ok())
+}
+
+/// The branch to upload to from `dir`: the checked out one, renamed through `branch_map`.
+pub fn upload_branch(dir: &Path, branch_map: &HashMap<String, String>) -> Option<String> {
+    let branch = current_branch(dir)?;
+    Some(branch_map.get(&branch).cloned().unwrap_or(branch))
+}
+
+/// Warn if what's uploaded from `dir` might not be reproducible from the remote.
+pub fn warn_unpublished(dir: &Path) {
+    if revision(dir).is_none() {
+        return;
+    }
+    if is_dirty(dir) == Some(true) {
+        println!(
+            "WARN: {}",
+            "uploading from a dirty tree, the revision won't match what's built".yellow()
+        );
+    }
+    match unpushed(dir) {
+        None => println!("WARN: {}", "the current branch has no upstream".yellow()),
+        Some(0) => {}
+        Some(x) => println!("WARN: {}", format!("{} commits are not pushed", x).yellow()),
+    }
+}
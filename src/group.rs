@@ -0,0 +1,172 @@
+use colored::Colorize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{json_print, print_exit, table_print, Deriv, Fonal, HttpResponse, OutputFormat, DB};
+
+/// A named set of hosts, like `role:builder` or `site:home`, stored on the server.
+/// An entry uploaded under the group's name is applied by every member
+/// that has no entry of its own.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Group {
+    pub name: String,
+    pub hosts: Vec<String>,
+}
+
+fn get_groups() -> Vec<Group> {
+    match DB::get_groups() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get host groups".red()), 1),
+    }
+}
+
+pub fn members(group: &str) -> Option<Vec<String>> {
+    get_groups()
+        .into_iter()
+        .find(|x| x.name == group)
+        .map(|x| x.hosts)
+}
+
+/// Find the entry for `name` on `branch`: its own, otherwise the one of the single group
+/// it belongs to that has an entry on that branch.
+pub fn resolve_entry(name: &str, branch: &str) -> Result<Deriv, String> {
+    if let Some(deriv) = DB::get_entry(name, branch)? {
+        return Ok(deriv);
+    }
+    let groups = DB::get_groups().ok_or(format!(
+        "no derivation for {} on the branch {} and failed to get its groups",
+        name, branch
+    ))?;
+    let mut candidates = Vec::new();
+    for group in groups.iter().filter(|x| x.hosts.iter().any(|x| x == name)) {
+        if let Some(deriv) = DB::get_entry(&group.name, branch)? {
+            candidates.push(deriv);
+        }
+    }
+    match candidates.len() {
+        0 => Err(format!(
+            "no derivation for {} or its groups on the branch {}",
+            name, branch
+        )),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => Err(format!(
+            "{} is in more than one group with an entry on {}: {}",
+            name,
+            branch,
+            candidates
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Tell that `name` got the entry of its group, if `resolve_entry` fell back to one.
+pub fn print_follows(name: &str, deriv: &Deriv) {
+    if deriv.name != name {
+        println!("INFO: {} follows the group {}", name, deriv.name.green());
+    }
+}
+
+/// `resolve_entry` over already fetched derivations and groups: the entry of `name` on
+/// `branch`, otherwise the one of the single group it belongs to with an entry there.
+pub fn entry_of<'a>(
+    derivations: &'a [Deriv],
+    groups: &[Group],
+    name: &str,
+    branch: &str,
+) -> Option<&'a Deriv> {
+    let on_branch = |entry: &str| {
+        derivations
+            .iter()
+            .find(|x| x.name == entry && x.branch == branch)
+    };
+    if let Some(deriv) = on_branch(name) {
+        return Some(deriv);
+    }
+    let mut candidates = groups
+        .iter()
+        .filter(|x| x.hosts.iter().any(|host| host == name))
+        .filter_map(|x| on_branch(&x.name));
+    match (candidates.next(), candidates.next()) {
+        (Some(deriv), None) => Some(deriv),
+        _ => None,
+    }
+}
+
+fn print_res(res: HttpResponse, group: &str) {
+    if res.status.success() {
+        println!("{}: {}", group, res.body.green());
+    } else {
+        print_exit(
+            &format!(
+                "ERROR: \"{}\": {}; {} {}",
+                group,
+                res.body.red(),
+                res.status.status_code,
+                res.status.status_message
+            ),
+            1,
+        );
+    }
+}
+
+pub fn handle_group_ls(output: OutputFormat) {
+    let groups = get_groups();
+    if output == OutputFormat::Json {
+        return json_print(&groups);
+    }
+
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for group in groups {
+        table.push(vec![
+            group.name.into(),
+            group.hosts.len().to_string().into(),
+            group.hosts.join(", ").into(),
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Group".to_owned()),
+        Fonal::String("Size".to_owned()),
+        Fonal::String("Hosts".to_owned()),
+    ]);
+
+    table_print::<3>(table);
+}
+
+pub fn handle_group_add(group: String, hosts: Vec<String>) {
+    let mut current = members(&group).unwrap_or_default();
+    for host in hosts {
+        if !current.contains(&host) {
+            current.push(host);
+        }
+    }
+    print_res(
+        DB::set_group(&Group {
+            name: group.clone(),
+            hosts: current,
+        }),
+        &group,
+    );
+}
+
+/// Remove hosts from a group, or the whole group when no hosts are given.
+pub fn handle_group_rm(group: String, hosts: Vec<String>) {
+    let current = match members(&group) {
+        Some(x) => x,
+        None => print_exit(
+            &format!("ERROR: {}", format!("no group named {}", group).red()),
+            1,
+        ),
+    };
+    if hosts.is_empty() {
+        return print_res(DB::delete_group(&group), &group);
+    }
+    print_res(
+        DB::set_group(&Group {
+            name: group.clone(),
+            hosts: current.into_iter().filter(|x| !hosts.contains(x)).collect(),
+        }),
+        &group,
+    );
+}
@@ -0,0 +1,185 @@
+use std::io::Write;
+
+use colored::Colorize;
+
+use crate::{pretty_print, print_exit, store_path::STORE_DIR, Deriv, DB};
+
+/// Shortest hash prefix that is looked up, anything shorter only matches names.
+const MIN_HASH_PREFIX: usize = 4;
+
+fn hash_part(store_hash: &str) -> &str {
+    store_hash
+        .strip_prefix(STORE_DIR)
+        .and_then(|x| x.strip_prefix('/'))
+        .unwrap_or(store_hash)
+}
+
+fn is_hash_match(deriv: &Deriv, query_hash: &str) -> bool {
+    query_hash.len() >= MIN_HASH_PREFIX && hash_part(&deriv.storeHash).starts_with(query_hash)
+}
+
+/// `needle`'s characters appear in `haystack` in order, like `elna` in `elaina`.
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|x| haystack.any(|y| y == x))
+}
+
+/// Entries matching `query`, trying from most to least exact and stopping at the first
+/// kind that matches anything: the exact name, a store hash prefix, a name containing
+/// the query, then a name containing its characters in order.
+pub fn find<'a>(derivations: &'a [Deriv], query: &str) -> Vec<&'a Deriv> {
+    let query_hash = hash_part(query);
+    let query_lower = query.to_lowercase();
+    let matchers: [&dyn Fn(&Deriv) -> bool; 4] = [
+        &|x| x.name == query,
+        &|x| is_hash_match(x, query_hash),
+        &|x| x.name.to_lowercase().contains(&query_lower),
+        &|x| is_subsequence(&query_lower, &x.name.to_lowercase()),
+    ];
+    for matcher in matchers {
+        let found: Vec<&Deriv> = derivations.iter().filter(|x| matcher(x)).collect();
+        if !found.is_empty() {
+            return found;
+        }
+    }
+    Vec::new()
+}
+
+/// Print the candidates and exit, for when a query didn't pick a single entry.
+pub fn exit_ambiguous(query: &str, candidates: Vec<Deriv>) -> ! {
+    if candidates.is_empty() {
+        print_exit(
+            &format!("ERROR: {}", format!("nothing matches \"{}\"", query).red()),
+            1,
+        );
+    }
+    println!(
+        "ERROR: {}",
+        format!("\"{}\" matches more than one derivation:", query).red()
+    );
+    pretty_print(candidates, "");
+    std::process::exit(1);
+}
+
+/// The single entry matching `query`(on `branch` if given), exiting with the candidates if
+/// there isn't exactly one.
+pub fn resolve_one(query: &str, branch: Option<&str>) -> Deriv {
+    let derivations: Vec<Deriv> = DB::get_all()
+        .unwrap()
+        .into_iter()
+        .filter(|x| branch.is_none_or(|branch| x.branch == branch))
+        .collect();
+    let mut found: Vec<Deriv> = find(&derivations, query).into_iter().cloned().collect();
+    if found.len() != 1 {
+        exit_ambiguous(query, found);
+    }
+    found.pop().unwrap()
+}
+
+/// Entries whose store hash starts with `query`, with or without the store dir. Nothing
+/// matches a query shorter than `MIN_HASH_PREFIX`.
+pub fn find_hash<'a>(derivations: &'a [Deriv], query: &str) -> Vec<&'a Deriv> {
+    let query_hash = hash_part(query);
+    derivations
+        .iter()
+        .filter(|x| is_hash_match(x, query_hash))
+        .collect()
+}
+
+/// The single entry whose store hash starts with `query`, exiting with the candidates if
+/// there isn't exactly one. Names don't match, so a hash can't pick a different host.
+pub fn resolve_hash(query: &str) -> Deriv {
+    if hash_part(query).len() < MIN_HASH_PREFIX {
+        print_exit(
+            &format!(
+                "ERROR: {}",
+                format!(
+                    "a hash prefix needs at least {} characters",
+                    MIN_HASH_PREFIX
+                )
+                .red()
+            ),
+            1,
+        );
+    }
+    let derivations = DB::get_all().unwrap();
+    let mut found: Vec<Deriv> = find_hash(&derivations, query)
+        .into_iter()
+        .cloned()
+        .collect();
+    if found.len() != 1 {
+        exit_ambiguous(query, found);
+    }
+    found.pop().unwrap()
+}
+
+/// Show the entries `query` matched without naming them and ask before acting on them.
+pub fn confirm_matches(query: &str, matches: Vec<Deriv>, action: &str) -> bool {
+    println!("INFO: \"{}\" is not a name, it matches:", query);
+    pretty_print(matches, "");
+    print!("{} {}? [y/N]: ", "WARN:".yellow(), action);
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).is_err() {
+        return false;
+    }
+    matches!(line.trim(), "y" | "Y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deriv(name: &str, hash: &str) -> Deriv {
+        Deriv {
+            id: None,
+            name: name.to_owned(),
+            storeHash: format!("{}/{}-nixos-system-{}", STORE_DIR, hash, name),
+            branch: "main".to_owned(),
+            force: None,
+            date_added: None,
+            provenance: Default::default(),
+            signature: None,
+        }
+    }
+
+    fn derivations() -> Vec<Deriv> {
+        vec![
+            deriv("elaina", "0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x"),
+            deriv("web", "0c7cqyd3s7n7m4k1dp3rq7g0f6yxwhv2"),
+            deriv("web-staging", "9v5d40jyvmwgnq1nj8f19ji2rcc5dksb"),
+        ]
+    }
+
+    fn names(found: Vec<&Deriv>) -> Vec<&str> {
+        found.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn exact_name_wins() {
+        let derivations = derivations();
+        assert_eq!(names(find(&derivations, "web")), ["web"]);
+    }
+
+    #[test]
+    fn hash_prefixes() {
+        let derivations = derivations();
+        assert_eq!(names(find(&derivations, "9v5d")), ["web-staging"]);
+        assert_eq!(names(find(&derivations, "/nix/store/0c7c8")), ["elaina"]);
+        // Shared by two entries
+        assert_eq!(names(find(&derivations, "0c7c")), ["elaina", "web"]);
+        assert_eq!(names(find_hash(&derivations, "0c7c")), ["elaina", "web"]);
+        // Too short for a hash, and no name has these letters in order
+        assert!(find(&derivations, "0c7").is_empty());
+        assert!(find_hash(&derivations, "9v5").is_empty());
+    }
+
+    #[test]
+    fn parts_of_names() {
+        let derivations = derivations();
+        assert_eq!(names(find(&derivations, "STAG")), ["web-staging"]);
+        assert_eq!(names(find(&derivations, "elna")), ["elaina"]);
+        assert_eq!(names(find(&derivations, "we")), ["web", "web-staging"]);
+        assert!(find_hash(&derivations, "elaina").is_empty());
+    }
+}
@@ -1,16 +1,37 @@
 use chrono::{DateTime, Local};
-use clap::{ArgAction, Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use colored::{ColoredString, Colorize};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::{BufRead, Read, Write};
+use std::fs;
+use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::path::Path;
 use std::process::{Command, ExitStatus, Stdio};
-use std::{fs, u16};
 
+mod agent;
+mod batch;
+mod build;
+mod config;
+mod deploy;
+mod fleet;
+mod generations;
+mod git;
+mod group;
+mod lookup;
+mod narinfo;
+mod policy;
+mod preflight;
+mod progress;
+mod provenance;
+mod pull;
+mod reboot;
+mod rollout;
+mod signature;
 mod ssh_agent;
+mod status;
+mod store_path;
+mod substituter;
 
 const PRIV_HOST: &str = "10.100.0.1";
 // const HOST: &str = "localhost";
@@ -26,6 +47,20 @@ const PUB_HOST: &str = "https://api.tami.moe";
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Output format of listings
+    #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+    /// Show nix's own logs instead of a progress bar
+    #[arg(long, global = true)]
+    verbose: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Human readable table
+    Table,
+    /// JSON, for scripts
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -33,6 +68,18 @@ enum Commands {
     /// Configure gc roots derivations on server or apply them to local computer
     // #[clap(alias = "derivations")]
     Deriv(DerivArgs),
+    /// Overview of every host known to the server
+    Fleet(FleetArgs),
+    /// Manage host groups like `role:builder`; an entry named after a group is applied by its members
+    Group(GroupArgs),
+    /// Move an entry to a new build a few hosts at a time
+    Rollout(RolloutArgs),
+    /// Keep this host on its branch's derivation, switching inside maintenance windows(run as root)
+    Agent {
+        /// Poll once and exit instead of looping forever
+        #[arg(long, action = ArgAction::SetTrue)]
+        once: bool,
+    },
     /// Sudo, but request the password visually using `rofi -dmenu -password`; this might not be a safe idea tho
     Sudo(SudoArgs),
 }
@@ -43,6 +90,21 @@ struct DerivArgs {
     command: DerivCommands,
 }
 #[derive(Args)]
+struct FleetArgs {
+    #[command(subcommand)]
+    command: FleetCommands,
+}
+#[derive(Args)]
+struct RolloutArgs {
+    #[command(subcommand)]
+    command: RolloutCommands,
+}
+#[derive(Args)]
+struct GroupArgs {
+    #[command(subcommand)]
+    command: GroupCommands,
+}
+#[derive(Args)]
 struct SudoArgs {
     /// The program to run with sudo
     program: String,
@@ -52,17 +114,79 @@ struct SudoArgs {
 enum DerivCommands {
     /// Make a gc root on server
     Up {
-        name: String,
-        store_hash: String,
-        #[arg(long, short, default_value = "main")]
+        /// Host or group(see `gurl group`) the derivation is for
+        #[arg(required_unless_present = "from_file")]
+        name: Option<String>,
+        /// A store path, a `result` symlink or a flake installable like `.#nixosConfigurations.x.config.system.build.toplevel`
+        #[arg(required_unless_present = "from_file")]
+        store_hash: Option<String>,
+        /// Upload many entries at once from a JSON array or JSON lines of
+        /// `{"name", "storeHash", "branch"}`(or `nix build --json` output), `-` reads stdin
+        #[arg(long, conflicts_with_all = ["name", "store_hash"])]
+        from_file: Option<String>,
+        /// The current git branch(see `branch_map` in the config), otherwise main
+        #[arg(long, short)]
+        branch: Option<String>,
+        #[clap(long, short, action = ArgAction::SetTrue)]
+        force: Option<bool>,
+        /// Note stored with the derivation, shown by `show` and `log`
+        #[arg(long, short)]
+        message: Option<String>,
+        /// Allow store paths that aren't a NixOS system
+        #[clap(long, action = ArgAction::SetTrue)]
+        any_path: bool,
+    },
+    /// Build nixosConfigurations of a flake and upload each under its name
+    BuildUp {
+        /// `<flake>#<host>`, or just `<flake>` with --all
+        flake: String,
+        /// Build every configuration of the flake
+        #[clap(long, action = ArgAction::SetTrue)]
+        all: bool,
+        /// The current git branch of the flake by default
+        #[arg(long, short)]
         branch: Option<String>,
+        /// Passed on to `nix build --builders`
+        #[arg(long)]
+        builders: Option<String>,
         #[clap(long, short, action = ArgAction::SetTrue)]
         force: Option<bool>,
+        /// Note stored with the derivations, shown by `show` and `log`
+        #[arg(long, short)]
+        message: Option<String>,
     },
     /// List all derivations on server(in the DB)
     Ls {},
+    /// Show everything known about one derivation
+    Show {
+        /// Name, store hash prefix, part of a name or its letters in order; the first of
+        /// these that matches anything is used
+        name: String,
+        #[arg(long, short)]
+        branch: Option<String>,
+    },
+    /// Show what changed between the closures of two derivations
+    Diff {
+        /// Name, store hash prefix, part of a name or its letters in order; the first of
+        /// these that matches anything is used
+        from: String,
+        /// Name, store hash prefix, part of a name or its letters in order; the first of
+        /// these that matches anything is used
+        to: String,
+        #[arg(long, short)]
+        branch: Option<String>,
+    },
+    /// List derivations newest first, with where they came from
+    Log {
+        /// Only this name
+        name: Option<String>,
+    },
     /// Delete the given name on a given branch.
     /// "_" means wildcard ; `gurl deriv del auto-merge _`
+    ///
+    /// A name that isn't an exact match is taken as a store hash prefix(at least 4
+    /// characters) of a single entry, which is shown and asked for before deleting.
+    /// Parts of names don't match here, unlike in `show` and `diff`
     Del {
         branch: String,
         #[clap(default_value = "_")]
@@ -74,15 +198,169 @@ enum DerivCommands {
         name: Option<String>,
         #[arg(long, short, default_value = "main")]
         branch: Option<String>,
+        /// Apply the derivation with this store hash(or a unique prefix of at least 4 characters) instead,
+        /// names don't match here
+        #[arg(long, conflicts_with = "branch")]
+        hash: Option<String>,
+        /// Apply even if the host is on hold or outside its maintenance windows
+        #[clap(long, action = ArgAction::SetTrue)]
+        ignore_hold: bool,
+        /// Apply even if a pre-flight check(disk space, architecture, hostname, running
+        /// activation) fails
+        #[clap(long, action = ArgAction::SetTrue)]
+        skip_checks: bool,
+        /// Reboot after switching if the kernel, initrd or systemd changed
+        #[clap(long, action = ArgAction::SetTrue)]
+        reboot_if_needed: bool,
+    },
+    /// Copy a host's derivation to it over ssh and switch it there
+    Deploy {
+        #[arg(required_unless_present_any = ["all", "group"])]
+        host: Option<String>,
+        /// Deploy to every host with an entry on the branch, or in a group with one
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["host", "group"])]
+        all: bool,
+        /// Deploy to every member of this group
+        #[arg(long, short, conflicts_with = "host")]
+        group: Option<String>,
+        /// Hosts deployed to at the same time
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+        #[arg(long, short, default_value = "main")]
+        branch: String,
+        /// Address to ssh to, the host name by default(only for a single host)
+        #[arg(long, short)]
+        address: Option<String>,
+        /// User to ssh as, `$USER` by default; anyone but root uses sudo on the host
+        #[arg(long, short)]
+        user: Option<String>,
+        /// Deploy to hosts that are on hold or outside their maintenance windows too,
+        /// instead of skipping them
+        #[clap(long, action = ArgAction::SetTrue)]
+        ignore_hold: bool,
+    },
+    /// Freeze a host so neither `apply` nor the agent switches it
+    Hold {
+        name: String,
+        /// Lift the hold automatically at this date(YYYY-MM-DD [HH:MM])
+        #[arg(long, short)]
+        until: Option<String>,
+        /// Keep the host on this store path while held
+        #[arg(long, short)]
+        pin: Option<String>,
+        /// Why the host is held, shown to whoever tries to apply
+        #[arg(long, short)]
+        message: Option<String>,
+    },
+    /// Lift the hold of a host
+    Unhold { name: String },
+    /// Set the maintenance windows of a host, like `Sat,Sun@22:00-05:00`; none clears them
+    Window { name: String, windows: Vec<String> },
+    /// Whether this machine runs its derivation; exits with 0 when up to date, 2 when
+    /// behind, 3 when ahead and 4 when locally modified
+    Status {
+        #[arg(long, short, default_value = "$HOSTNAME")]
+        name: Option<String>,
+        #[arg(long, short, default_value = "main")]
+        branch: Option<String>,
+    },
+    /// Download a derivation from the server without applying it, for a later `apply`
+    Pull {
+        #[arg(long, short, default_value = "$HOSTNAME")]
+        name: Option<String>,
+        #[arg(long, short, default_value = "main")]
+        branch: Option<String>,
+    },
+    /// List the generations of the system profile with the entries they match
+    Generations {},
+    /// Delete old generations of the system profile, keeping the newest ones, the current
+    /// and booted ones and those the server has an entry for
+    GcLocal {
+        /// How many of the newest generations to keep
+        #[arg(long, short, default_value_t = 5)]
+        keep: usize,
+        /// Host whose server entries are kept
+        #[arg(long, short, default_value = "$HOSTNAME")]
+        name: Option<String>,
+        /// Run `nix-store --gc` afterwards
+        #[clap(long, action = ArgAction::SetTrue)]
+        gc: bool,
+        /// Only show what would be deleted
+        #[clap(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
     },
     /// Rollback the current nixos profile
-    Rollback {},
+    Rollback {
+        /// Switch to this generation(see `gurl deriv generations`) instead of the previous one
+        #[arg(long)]
+        to: Option<u32>,
+    },
     /// Reapply the current system(run switch-to-configuration)
     Reapply {},
+    /// Report the currently running system to the server
+    Report {
+        #[arg(long, short, default_value = "$HOSTNAME")]
+        name: Option<String>,
+        #[arg(long, short, default_value = "main")]
+        branch: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RolloutCommands {
+    /// Set the entry to a new store hash, letting the hosts follow it in waves
+    Start {
+        /// The entry the hosts follow, usually a group(see `gurl group`)
+        name: String,
+        store_hash: String,
+        #[arg(long, short, default_value = "main")]
+        branch: String,
+        /// Hosts to roll out to, comma separated
+        #[arg(long, value_delimiter = ',', required_unless_present = "group")]
+        hosts: Vec<String>,
+        /// Roll out to the members of this group
+        #[arg(long, short, conflicts_with = "hosts")]
+        group: Option<String>,
+        /// Hosts in the first wave
+        #[arg(long, default_value_t = 1)]
+        canaries: usize,
+        /// Hosts in every following wave
+        #[arg(long, default_value_t = 5)]
+        batch_size: usize,
+        /// Revert the entry once more hosts than this failed
+        #[arg(long, default_value_t = 0)]
+        max_failures: usize,
+        /// Seconds a host of the current wave has to report back
+        #[arg(long, default_value_t = 1800)]
+        timeout: u64,
+    },
+    /// List rollouts and their state
+    Ls {},
+}
+
+#[derive(Subcommand)]
+enum GroupCommands {
+    /// List all groups and their hosts
+    Ls {},
+    /// Add hosts to a group, creating it if needed
+    Add { group: String, hosts: Vec<String> },
+    /// Remove hosts from a group; without hosts the whole group is removed
+    Rm { group: String, hosts: Vec<String> },
+}
+
+#[derive(Subcommand)]
+enum FleetCommands {
+    /// Show what every host should run and what it last reported running
+    Status {
+        /// Hours without a report after which a host counts as silent
+        #[arg(long, default_value_t = 24)]
+        silent_after: i64,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
+    progress::set_verbose(cli.verbose);
 
     match &cli.command {
         Commands::Deriv(derivargs) => match &derivargs.command {
@@ -91,14 +369,159 @@ fn main() {
                 store_hash,
                 branch,
                 force,
-            } => handle_deriv_upload(name, store_hash, branch.clone(), force.clone()),
-            DerivCommands::Ls {} => handle_deriv_ls(),
-            DerivCommands::Apply { name, branch } => {
-                handle_deriv_apply(name.clone().unwrap(), branch.clone().unwrap())
+                message,
+                any_path,
+                from_file,
+            } => match from_file {
+                Some(file) => batch::handle_deriv_upload_batch(
+                    file,
+                    branch.clone(),
+                    *force,
+                    message.clone(),
+                    *any_path,
+                ),
+                None => handle_deriv_upload(
+                    name.as_deref().unwrap(),
+                    store_hash.as_deref().unwrap(),
+                    branch.clone(),
+                    *force,
+                    message.clone(),
+                    *any_path,
+                ),
+            },
+            DerivCommands::BuildUp {
+                flake,
+                all,
+                branch,
+                builders,
+                force,
+                message,
+            } => build::handle_deriv_build_up(
+                flake.clone(),
+                *all,
+                branch.clone(),
+                builders.clone(),
+                *force,
+                message.clone(),
+            ),
+            DerivCommands::Show { name, branch } => {
+                handle_deriv_show(cli.output, name, branch.as_deref())
+            }
+            DerivCommands::Diff { from, to, branch } => {
+                handle_deriv_diff(from, to, branch.as_deref())
+            }
+            DerivCommands::Log { name } => handle_deriv_log(cli.output, name.as_deref()),
+            DerivCommands::Ls {} => handle_deriv_ls(cli.output),
+            DerivCommands::Apply {
+                name,
+                branch,
+                hash,
+                ignore_hold,
+                skip_checks,
+                reboot_if_needed,
+            } => handle_deriv_apply(
+                name.clone().unwrap(),
+                branch.clone().unwrap(),
+                hash.as_deref(),
+                *ignore_hold,
+                *skip_checks,
+                *reboot_if_needed,
+            ),
+            DerivCommands::Deploy {
+                host,
+                all,
+                group,
+                jobs,
+                branch,
+                address,
+                user,
+                ignore_hold,
+            } => deploy::handle_deriv_deploy(
+                host.clone(),
+                *all,
+                group.clone(),
+                branch.clone(),
+                address.clone(),
+                user.clone(),
+                *jobs,
+                *ignore_hold,
+            ),
+            DerivCommands::Hold {
+                name,
+                until,
+                pin,
+                message,
+            } => {
+                policy::handle_deriv_hold(name.clone(), until.clone(), pin.clone(), message.clone())
+            }
+            DerivCommands::Unhold { name } => policy::handle_deriv_unhold(name.clone()),
+            DerivCommands::Window { name, windows } => {
+                policy::handle_deriv_window(name.clone(), windows.clone())
             }
             DerivCommands::Del { branch, name } => handle_deriv_del(branch.clone(), name.clone()),
-            DerivCommands::Rollback {} => handle_deriv_rollback(),
+            DerivCommands::Status { name, branch } => status::handle_deriv_status(
+                cli.output,
+                name.clone().unwrap(),
+                branch.clone().unwrap(),
+            ),
+            DerivCommands::Pull { name, branch } => {
+                pull::handle_deriv_pull(name.clone().unwrap(), branch.clone().unwrap())
+            }
+            DerivCommands::Generations {} => generations::handle_deriv_generations(cli.output),
+            DerivCommands::GcLocal {
+                keep,
+                name,
+                gc,
+                dry_run,
+            } => generations::handle_deriv_gc_local(name.clone().unwrap(), *keep, *gc, *dry_run),
+            DerivCommands::Rollback { to } => handle_deriv_rollback(*to),
             DerivCommands::Reapply {} => handle_deriv_reapply(),
+            DerivCommands::Report { name, branch } => {
+                handle_deriv_report(name.clone().unwrap(), branch.clone().unwrap())
+            }
+        },
+        Commands::Rollout(rolloutargs) => match &rolloutargs.command {
+            RolloutCommands::Start {
+                name,
+                store_hash,
+                branch,
+                hosts,
+                group,
+                canaries,
+                batch_size,
+                max_failures,
+                timeout,
+            } => rollout::handle_rollout_start(
+                name.clone(),
+                store_hash.clone(),
+                branch.clone(),
+                match group {
+                    Some(group) => group::members(group).unwrap_or_else(|| {
+                        print_exit(&format!("ERROR: no group named {}", group.red()), 1)
+                    }),
+                    None => hosts.clone(),
+                },
+                *canaries,
+                *batch_size,
+                *max_failures,
+                *timeout,
+            ),
+            RolloutCommands::Ls {} => rollout::handle_rollout_ls(cli.output),
+        },
+        Commands::Group(groupargs) => match &groupargs.command {
+            GroupCommands::Ls {} => group::handle_group_ls(cli.output),
+            GroupCommands::Add { group, hosts } => {
+                group::handle_group_add(group.clone(), hosts.clone())
+            }
+            GroupCommands::Rm { group, hosts } => {
+                group::handle_group_rm(group.clone(), hosts.clone())
+            }
+        },
+        Commands::Agent { once } => agent::handle_agent(*once),
+        Commands::Fleet(fleetargs) => match &fleetargs.command {
+            FleetCommands::Status { silent_after } => {
+                fleet::handle_fleet_status(cli.output, *silent_after)
+            }
         },
         Commands::Sudo(sudo_args) => {
             let password = String::from_utf8_lossy(
@@ -181,10 +604,9 @@ fn handle_deriv_del(branch: String, name: String) {
             );
             std::process::exit(1);
         }
-        return res.status.success();
+        res.status.success()
     }
 
-    //TODO: rework so no 2 for loops when there is *1* line diff... smh
     if branch != "_" && name == "_" {
         let mut successfull = false;
         for deriv in DB::get_all()
@@ -202,19 +624,30 @@ fn handle_deriv_del(branch: String, name: String) {
             );
             std::process::exit(1);
         }
-    } else if branch == "_" && name != "_" {
-        let mut successfull = false;
-        for deriv in DB::get_all()
+    } else if name != "_" {
+        let derivations: Vec<Deriv> = DB::get_all()
             .unwrap()
             .into_iter()
-            .filter(|x| x.name == name)
-        {
-            let res = DB::delete(&deriv.name, &deriv.branch);
-            successfull |= print_res(res, deriv.name, deriv.branch);
+            .filter(|x| branch == "_" || x.branch == branch)
+            .collect();
+        let mut found: Vec<&Deriv> = derivations.iter().filter(|x| x.name == name).collect();
+        // Not a name, so it may be the hash of exactly one entry
+        if found.is_empty() {
+            found = lookup::find_hash(&derivations, &name);
+            if found.is_empty() {
+                println!("ERROR: {}", "Failed to find that name on any branch.".red());
+                std::process::exit(1);
+            }
+            if found.len() > 1 {
+                lookup::exit_ambiguous(&name, found.into_iter().cloned().collect());
+            }
+            if !lookup::confirm_matches(&name, vec![found[0].clone()], "Delete it") {
+                print_exit("INFO: nothing deleted", 1);
+            }
         }
-        if !successfull {
-            println!("ERROR: {}", "Failed to find that name on any branch.".red());
-            std::process::exit(1);
+        for deriv in found {
+            let res = DB::delete(&deriv.name, &deriv.branch);
+            print_res(res, deriv.name.clone(), deriv.branch.clone());
         }
     } else {
         let res = DB::delete(&name, &branch);
@@ -222,7 +655,7 @@ fn handle_deriv_del(branch: String, name: String) {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(non_snake_case)]
 struct Deriv {
     id: Option<i32>,
@@ -231,9 +664,48 @@ struct Deriv {
     branch: String,
     force: Option<bool>,
     date_added: Option<DateTime<Local>>,
+    #[serde(flatten)]
+    provenance: provenance::Provenance,
+    /// Made by the uploader over `signature::entry_fingerprint`.
+    signature: Option<String>,
 }
-fn make_req(location: &str, json: Option<&str>) -> Result<HttpResponse, HttpStatus> {
-    let mut stream = TcpStream::connect((PRIV_HOST, PRIV_PORT)).unwrap();
+
+/// What a host last told the server it is running.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct Report {
+    /// The entry the host follows, usually its own hostname.
+    name: String,
+    /// Hostname of the machine that sent the report.
+    #[serde(default)]
+    host: String,
+    branch: String,
+    storeHash: String,
+    date_switched: Option<DateTime<Local>>,
+    date_reported: Option<DateTime<Local>>,
+    outcome: Option<ApplyOutcome>,
+    /// The store hash the outcome is about, which after a failure isn't the running one.
+    #[serde(default)]
+    target: Option<String>,
+    /// The running kernel, initrd or systemd is older than the current system's.
+    #[serde(default)]
+    reboot_required: bool,
+}
+
+/// The result of the last apply attempt on a host.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ApplyOutcome {
+    Applied,
+    CopyFailed,
+    ActivationFailed,
+    HealthCheckFailed,
+    /// Not signed by a trusted key, so it wasn't activated.
+    VerificationFailed,
+}
+fn make_req(location: &str, json: Option<&str>) -> Result<HttpResponse, String> {
+    let mut stream = TcpStream::connect((PRIV_HOST, PRIV_PORT))
+        .map_err(|x| format!("connecting to {}:{}: {}", PRIV_HOST, PRIV_PORT, x))?;
     let request = format!(
         "{} HTTP/1.1\r\n\
         Host: {}\r\n\
@@ -247,23 +719,36 @@ fn make_req(location: &str, json: Option<&str>) -> Result<HttpResponse, HttpStat
         json.unwrap_or("{}")
     );
 
-    stream.write_all(request.as_bytes()).unwrap();
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|x| format!("sending the request: {}", x))?;
 
     let mut response = String::new();
-    stream.read_to_string(&mut response).unwrap();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|x| format!("reading the response: {}", x))?;
     let mut strs = response.split("\r\n\r\n");
-    let status = HttpStatus::parse(strs.next().unwrap()).unwrap();
+    let status = strs
+        .next()
+        .and_then(HttpStatus::parse)
+        .ok_or("the response has no status line".to_owned())?;
     match strs.next() {
         Some(body) => Ok(HttpResponse {
             body: body.to_string(),
             status,
         }),
-        None => Err(status),
+        None => Err(format!(
+            "the response has no body: {} {}",
+            status.status_code, status.status_message
+        )),
     }
 }
 
-fn handle_deriv_ls() {
+fn handle_deriv_ls(output: OutputFormat) {
     let derivations = DB::get_all().unwrap();
+    if output == OutputFormat::Json {
+        return json_print(&derivations);
+    }
     let current_system = fs::read_link("/run/current-system");
     match current_system {
         Ok(x) => match x.into_os_string().into_string() {
@@ -300,87 +785,153 @@ struct UploadHashAPI<'a> {
     branch: String,
     force: Option<bool>,
     date_added: DateTime<Local>,
+    #[serde(flatten)]
+    provenance: provenance::Provenance,
+    signature: Option<String>,
 }
-fn handle_deriv_upload(name: &str, hash: &str, branch: Option<String>, force: Option<bool>) {
-    let date_added = Local::now();
 
-    let payload = UploadHashAPI {
-        force,
-        storeHash: hash,
-        name,
-        date_added,
-        branch: match branch {
-            Some(x) => x,
-            None => "main".to_owned(),
-        },
+impl<'a> UploadHashAPI<'a> {
+    /// Signed with the `signing_key` of the config, if there is one.
+    fn new(
+        name: &'a str,
+        hash: &'a str,
+        branch: String,
+        force: Option<bool>,
+        provenance: provenance::Provenance,
+    ) -> Self {
+        let date_added = Local::now();
+        let signature = config::Config::load().signing_key.map(|path| {
+            let key = match signature::SecretKey::read(&path) {
+                Ok(x) => x,
+                Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+            };
+            let message =
+                signature::entry_fingerprint(name, &branch, hash, Some(date_added), &provenance);
+            key.sign(message.as_bytes())
+        });
+        UploadHashAPI {
+            storeHash: hash,
+            name,
+            branch,
+            force,
+            date_added,
+            provenance,
+            signature,
+        }
+    }
+}
+fn handle_deriv_upload(
+    name: &str,
+    hash: &str,
+    branch: Option<String>,
+    force: Option<bool>,
+    message: Option<String>,
+    any_path: bool,
+) {
+    let hash = match store_path::resolve(hash, any_path) {
+        Ok(x) => x.to_string(),
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
     };
-
-    let json_payload =
-        serde_json::to_string(&payload).expect("Failed to serialize payload to json.");
-
-    match make_upload_req(json_payload.clone()) {
+    let cwd = Path::new(".");
+    git::warn_unpublished(cwd);
+    let branch = branch
+        .or_else(|| git::upload_branch(cwd, &config::Config::load().branch_map))
+        .unwrap_or("main".to_owned());
+    println!("INFO: uploading to the branch {}", branch.green());
+    let provenance = provenance::Provenance::collect(None, message);
+    match upload_deriv(name, &hash, branch, force, provenance) {
         Ok(str) => print_exit(str.as_str(), 0),
-        Err(err) => match err {
-            UploadReqError::Comment(str) => {
-                println!("{}", str);
-                std::process::exit(1);
-            }
-            UploadReqError::StoreHashNotFound => {
-                println!("INFO: uploading derivation closure to elaina");
+        Err(str) => print_exit(str.as_str(), 1),
+    }
+}
 
-                let private_key = std::env::var("GURL_SSH_KEY");
-                let known_hosts_file = std::env::var("GURL_SSH_HOSTS");
+/// Register `hash` as `name` on `branch`, copying the closure to the server first if it
+/// doesn't have it yet.
+fn upload_deriv(
+    name: &str,
+    hash: &str,
+    branch: String,
+    force: Option<bool>,
+    provenance: provenance::Provenance,
+) -> Result<String, String> {
+    let payload = UploadHashAPI::new(name, hash, branch, force, provenance);
 
-                let out;
+    let json_payload =
+        serde_json::to_string(&payload).expect("Failed to serialize payload to json.");
 
-                if let Ok(priv_key) = private_key {
-                    println!("INFO: using ssh-agent and private key");
+    // Every destination gets the closure before the entry exists, not only the server
+    if store_path::is_valid(hash) {
+        println!("INFO: uploading derivation closure");
+        copy_to_server(&[hash])?;
+    } else {
+        println!(
+            "WARN: {}",
+            format!(
+                "{} is not in the local store, not copying it anywhere",
+                hash
+            )
+            .yellow()
+        );
+    }
+    match make_upload_req(json_payload) {
+        Ok(str) => Ok(str),
+        Err(UploadReqError::Comment(str)) => Err(str),
+        Err(UploadReqError::StoreHashNotFound) => Err(format!(
+            "ERROR: {}",
+            "the server doesn't have the derivation's closure".red()
+        )),
+    }
+}
 
-                    let mut agent =
-                        ssh_agent::SshAgent::new(priv_key).expect("Failed to create SshAgent");
-                    if let Ok(file_path) = known_hosts_file {
-                        println!("INFO: using known_hosts file");
-                        agent.add_ssh_opts(format!("-o UserKnownHostsFile={}", file_path))
-                    }
+/// Copy the closures of `paths` to every `copy_destinations` of the config, one `nix copy`
+/// per destination. Fails if any destination couldn't be reached by any of its URIs.
+fn copy_to_server(paths: &[&str]) -> Result<(), String> {
+    let agent = ssh_agent_from_env();
+    let mut failed = Vec::new();
+    for destination in config::Config::load().copy_destinations {
+        let copied = destination.uris().iter().any(|uri| {
+            println!("INFO: copying to {}", uri);
+            let mut cmd = Command::new("nix");
+            cmd.args(["copy", "--to", uri]).args(paths);
+            if let Some(agent) = &agent {
+                agent.prepare(&mut cmd);
+            }
+            let success = progress::run_nix(&mut cmd)
+                .expect("Could not run `nix` as `nix copy ...`")
+                .success();
+            if !success {
+                println!("WARN: {}", format!("`nix copy` to {} failed", uri).yellow());
+            }
+            success
+        });
+        if !copied {
+            failed.push(destination.uris().join(" or "));
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!(
+            "ERROR: {} {}",
+            "`nix copy ...` failed to".red(),
+            failed.join(", ")
+        ));
+    }
+    Ok(())
+}
 
-                    out = agent.run_cmd(
-                        Command::new("nix")
-                            .args(vec!["copy", "--to", "ssh://root@elaina.tami.moe", hash])
-                            .stdout(Stdio::inherit())
-                            .stderr(Stdio::inherit()),
-                    );
-                } else {
-                    let nix_copy = Command::new("nix")
-                        .args(vec!["copy", "--to", "ssh://root@elaina.tami.moe", hash])
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .output();
-                    out = nix_copy;
-                }
+/// An `SshAgent` holding `$GURL_SSH_KEY`(and trusting `$GURL_SSH_HOSTS`), if the key is set.
+fn ssh_agent_from_env() -> Option<ssh_agent::SshAgent> {
+    let priv_key = std::env::var("GURL_SSH_KEY").ok()?;
+    println!("INFO: using ssh-agent and private key");
 
-                let out = out.expect("Could not run `nix` as `nix copy ...`");
-                if !out.status.success() {
-                    print_exit("ERROR: `nix copy ...` failed", 1);
-                }
-                match make_upload_req(json_payload) {
-                    Ok(str) => {
-                        print_exit(str.as_str(), 0);
-                    }
-                    Err(err) => {
-                        match err {
-                            UploadReqError::Comment(str) => print_exit(str.as_str(), 1),
-                            UploadReqError::StoreHashNotFound => {
-                                print_exit("ERROR: failed to find derivation on server even after upload...", 1);
-                            }
-                        };
-                    }
-                };
-            }
-        },
-    };
+    let mut agent = ssh_agent::SshAgent::new(priv_key).expect("Failed to create SshAgent");
+    if let Ok(file_path) = std::env::var("GURL_SSH_HOSTS") {
+        println!("INFO: using known_hosts file");
+        agent.add_ssh_opts(format!("-o UserKnownHostsFile={}", file_path))
+    }
+    Some(agent)
 }
 
-fn print_exit(str: &str, code: i32) {
+fn print_exit(str: &str, code: i32) -> ! {
     println!("{}", str);
     std::process::exit(code);
 }
@@ -403,33 +954,35 @@ fn make_upload_req(json_payload: String) -> Result<String, UploadReqError> {
                     Err(UploadReqError::StoreHashNotFound)
                 } else {
                     Err(UploadReqError::Comment(format!(
-                        "{}\n{}",
-                        format!(
-                            "ERROR: {}; {} {}",
-                            "failed to upload derivation".red(),
-                            res.status.status_code,
-                            res.status.status_message,
-                        ),
-                        format!("\t{}", res.body)
+                        "ERROR: {}; {} {}\n\t{}",
+                        "failed to upload derivation".red(),
+                        res.status.status_code,
+                        res.status.status_message,
+                        res.body
                     )))
                 }
             }
         }
         Err(err) => Err(UploadReqError::Comment(format!(
             "ERROR: {}",
-            format!("Failed to parse response body! err: {}", err.status_message).red()
+            format!("Failed to parse response body! err: {}", err).red()
         ))),
     }
 }
 
+fn json_print<T: serde::Serialize + ?Sized>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("Failed to serialize output to json.")
+    );
+}
+
 // TODO: Add fix this term_lenght thingy...
 fn table_print<const N: usize>(mut table: Vec<Vec<Fonal>>) {
-    let termsize::Size { rows: _, cols } = termsize::get()
-        .or(Some(termsize::Size {
-            rows: 0,
-            cols: u16::MAX,
-        }))
-        .unwrap();
+    let termsize::Size { rows: _, cols } = termsize::get().unwrap_or(termsize::Size {
+        rows: 0,
+        cols: u16::MAX,
+    });
     let term_width = (cols - 4).into();
     let mut lengths: [usize; N] = [0; N];
     for row in &table {
@@ -475,7 +1028,7 @@ fn table_print<const N: usize>(mut table: Vec<Vec<Fonal>>) {
                     }
                     let end_str = fooon.to_string();
                     line_diff = end_str.len() - fooon.input.len();
-                    return end_str;
+                    end_str
                 } else {
                     format!("{:<width$}", str.to_string(), width = lengths[index])
                 }
@@ -505,12 +1058,6 @@ impl Fonal {
             Fonal::ColoredString(colored_string) => colored_string.input.to_owned(),
         }
     }
-    fn style(&self) -> colored::Style {
-        match self {
-            Fonal::String(_) => colored::Style::default(),
-            Fonal::ColoredString(colored_string) => colored_string.style,
-        }
-    }
     fn fgcolor(&self) -> Option<colored::Color> {
         match self {
             Fonal::String(_) => None,
@@ -529,18 +1076,20 @@ impl From<ColoredString> for Fonal {
         Fonal::ColoredString(value)
     }
 }
-impl ToString for Fonal {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Fonal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Fonal::String(s) => s.clone(),
-            Fonal::ColoredString(cs) => cs.to_string(),
+            Fonal::String(s) => write!(f, "{}", s),
+            Fonal::ColoredString(cs) => write!(f, "{}", cs),
         }
     }
 }
 
 fn pretty_print(derivations: Vec<Deriv>, curr_sys: &str) {
     let mut table: Vec<Vec<Fonal>> = Vec::new();
-    for der in derivations {
+    let paths: Vec<&str> = derivations.iter().map(|x| x.storeHash.as_str()).collect();
+    let substitutable = narinfo::substitutable(&config::Config::load().substituters, &paths);
+    for (der, substitutable) in derivations.into_iter().zip(substitutable) {
         let mut info = "";
         if Path::new(&der.storeHash).exists() {
             info = "Cached";
@@ -552,7 +1101,14 @@ fn pretty_print(derivations: Vec<Deriv>, curr_sys: &str) {
             der.name.into(),
             der.branch.into(),
             info.to_owned().into(),
+            if substitutable {
+                "yes".green()
+            } else {
+                "no".red()
+            }
+            .into(),
             handle_date_to_dynamic_info(der.date_added).into(),
+            der.provenance.short_rev().into(),
             der.storeHash.into(),
         ]);
     }
@@ -561,11 +1117,85 @@ fn pretty_print(derivations: Vec<Deriv>, curr_sys: &str) {
         Fonal::String("Name".to_owned()),
         Fonal::String("Branch".to_owned()),
         Fonal::String("".to_owned()),
+        Fonal::String("Substitutable".to_owned()),
         Fonal::String("Date Added".to_owned()),
+        Fonal::String("Rev".to_owned()),
         Fonal::String("Hash".to_owned()),
     ]);
 
-    table_print::<5>(table);
+    table_print::<7>(table);
+}
+
+fn handle_deriv_show(output: OutputFormat, name: &str, branch: Option<&str>) {
+    let deriv = lookup::resolve_one(name, branch);
+    if output == OutputFormat::Json {
+        return json_print(&deriv);
+    }
+    println!("{}:", deriv.name.green());
+    println!("\tbranch: {}", deriv.branch);
+    println!("\thash: {}", deriv.storeHash);
+    println!("\tdate: {}", handle_date_to_dynamic_info(deriv.date_added));
+    deriv.provenance.print();
+    match narinfo::NarInfo::find(&config::Config::load().substituters, &deriv.storeHash) {
+        Some((cache, info)) => info.print(&cache),
+        None => println!("\tcache: {}", "not substitutable".red()),
+    }
+}
+
+fn handle_deriv_diff(from: &str, to: &str, branch: Option<&str>) {
+    let from = lookup::resolve_one(from, branch);
+    let to = lookup::resolve_one(to, branch);
+    println!(
+        "INFO: {} ({}) -> {} ({})",
+        from.name, from.branch, to.name, to.branch
+    );
+    let substituters = config::Config::load().substituters;
+    for deriv in [&from, &to] {
+        if !copy_closure(&substituters, &deriv.storeHash, None) {
+            std::process::exit(1);
+        }
+    }
+    let status = Command::new("nix")
+        .args(["store", "diff-closures", &from.storeHash, &to.storeHash])
+        .status()
+        .expect("Could not run `nix store diff-closures`");
+    if !status.success() {
+        std::process::exit(1);
+    }
+}
+
+fn handle_deriv_log(output: OutputFormat, name: Option<&str>) {
+    let mut derivations: Vec<Deriv> = DB::get_all()
+        .unwrap()
+        .into_iter()
+        .filter(|x| name.is_none_or(|name| x.name == name))
+        .collect();
+    derivations.sort_by_key(|x| std::cmp::Reverse(x.date_added));
+    if output == OutputFormat::Json {
+        return json_print(&derivations);
+    }
+
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for der in derivations {
+        table.push(vec![
+            handle_date_to_dynamic_info(der.date_added).into(),
+            der.name.into(),
+            der.branch.into(),
+            der.provenance.short_rev().into(),
+            der.provenance.uploader.unwrap_or_default().into(),
+            der.provenance.message.unwrap_or_default().into(),
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Date Added".to_owned()),
+        Fonal::String("Name".to_owned()),
+        Fonal::String("Branch".to_owned()),
+        Fonal::String("Rev".to_owned()),
+        Fonal::String("Uploader".to_owned()),
+        Fonal::String("Message".to_owned()),
+    ]);
+
+    table_print::<6>(table);
 }
 
 // This function should
@@ -595,105 +1225,260 @@ fn handle_date_to_dynamic_info(date: Option<DateTime<Local>>) -> ColoredString {
     }
 }
 
-fn handle_deriv_apply(name: String, branch: String) {
-    let payload =
-        Deriv {
-            date_added: None,
-            force: None,
-            id: None,
-            branch,
-            storeHash: "".to_owned(),
-            name: if name == "$HOSTNAME" {
-                String::from_utf8_lossy(
-            &Command::new("hostname")
-                .output()
-                .expect("Could not run `hostname`(unix? command), consider adding --name manually.")
-                .stdout
-                .trim_ascii_end()
-        ).into_owned()
-            } else {
-                name
-            },
-        };
-    println!("INFO: name set as: {}", payload.name);
-    let json_payload =
-        serde_json::to_string(&payload).expect("Failed to serialize payload to json.");
-
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(format!("{PUB_HOST}/derivations/"))
-        .body(json_payload)
-        .send()
-        .unwrap()
-        .text()
-        .unwrap();
-
-    let deriv: Deriv = serde_json::from_str(&response).unwrap();
+fn handle_deriv_apply(
+    name: String,
+    branch: String,
+    hash: Option<&str>,
+    ignore_hold: bool,
+    skip_checks: bool,
+    reboot_if_needed: bool,
+) {
+    let deriv = match hash {
+        Some(hash) => lookup::resolve_hash(hash),
+        None => {
+            let name = resolve_name(name);
+            println!("INFO: name set as: {}", name);
+            let deriv = match group::resolve_entry(&name, &branch) {
+                Ok(x) => x,
+                Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+            };
+            group::print_follows(&name, &deriv);
+            deriv
+        }
+    };
 
     println!("INFO: this will be installed:");
     println!("\tname: {}", deriv.name);
     println!("\tbranch: {}", deriv.branch);
     println!("\thash: {}", deriv.storeHash);
     println!("\tdate: {}", handle_date_to_dynamic_info(deriv.date_added));
+    deriv.provenance.print();
+    match signature::check_entry(&deriv, &config::Config::load().publishers) {
+        Ok(Some(key)) => println!("\tsigned by: {}", key.green()),
+        Ok(None) => {}
+        Err(x) => print_exit(
+            &format!("ERROR: {}", format!("refusing to apply, {}", x).red()),
+            1,
+        ),
+    }
+
+    let host = resolve_name("$HOSTNAME".to_owned());
+    let blocker = match policy::Policy::get(&host) {
+        Ok(x) => x.blocker(Local::now()),
+        Err(x) => Some(format!("could not check the policy of {}: {}", host, x)),
+    };
+    if let Some(reason) = blocker {
+        println!("WARN: {}", reason.yellow());
+        if !ignore_hold {
+            print_exit(
+                &format!(
+                    "ERROR: {}",
+                    "refusing to apply, use --ignore-hold to override".red()
+                ),
+                1,
+            );
+        }
+        if !policy::confirm_ignore(&host) {
+            print_exit("ERROR: aborted", 1);
+        }
+    }
+
+    let substituters = config::Config::load().substituters;
+    // Walked once, for the disk space check and the download estimate
+    let estimate = (!store_path::is_valid(&deriv.storeHash))
+        .then(|| narinfo::estimate(&substituters, &deriv.storeHash));
+    if !preflight::run(&deriv, &host, &substituters, estimate.as_ref()) {
+        if !skip_checks {
+            print_exit(
+                &format!(
+                    "ERROR: {}",
+                    "pre-flight checks failed, use --skip-checks to apply anyway".red()
+                ),
+                1,
+            );
+        }
+        println!(
+            "WARN: {}",
+            "applying despite failed pre-flight checks".yellow()
+        );
+    }
 
     let password = sudo_password_getter().expect("Failed to get sudo password");
 
-    if !Path::new(&deriv.storeHash).exists() {
-        let mut cmd = Command::new("nix")
-            .arg("copy")
-            .arg("--from")
-            // .arg("ssh://root@elaina.tami.moe")
-            .arg("https://nix-cache.tami.moe")
-            .arg(&deriv.storeHash)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .expect("Some error with running nix-copy-closure.");
-
-        // let mut cmd = Command::new("nix-copy-closure")
-        //     .arg("--gzip")
-        //     .arg("--from")
-        //     .arg("root@elaina.tami.moe")
-        //     .arg(&deriv.storeHash)
-        //     .stdout(Stdio::inherit())
-        //     .stderr(Stdio::inherit())
-        //     .spawn()
-        //     .expect("Some error with running nix-copy-closure.");
-        let status = cmd.wait();
-        match status {
-            Ok(exit_status) => {
-                if exit_status.success() {
-                    println!("INFO: Closure has finished copying")
-                } else {
-                    println!("ERROR: {}", "Error during closure copy!".red());
-                    return;
-                }
-            }
-            Err(_) => {
-                println!("ERROR: {}", "Failed to start closure copy!".red());
-                return;
-            }
-        };
+    if !copy_closure(&substituters, &deriv.storeHash, estimate.as_ref()) {
+        return;
     }
-    let mut cmd = Command::new("sudo")
-        .args(vec!["-S", "gurl-apply-helper", &deriv.storeHash])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .expect("Some error with starting gurl-apply-helper.");
-    let mut stdin = cmd.stdin.take().expect("Failed to open stdin");
-    std::thread::spawn(move || stdin.write_all(password.as_bytes()));
-    let status = cmd.wait();
-    match status {
-        Ok(exit_status) => {
-            if exit_status.success() {
-                println!("INFO: {}", "Successfully instaleld the closure!".green());
-            } else {
+    if let Err(x) =
+        signature::check_toplevel(&deriv.storeHash, &config::Config::load().trusted_keys)
+    {
+        print_exit(
+            &format!("ERROR: {}", format!("refusing to apply, {}", x).red()),
+            1,
+        );
+    }
+    if run_apply_helper(&deriv.storeHash, Some(password.clone())) {
+        println!("INFO: {}", "Successfully instaleld the closure!".green());
+        pull::remove_gc_root(&deriv.name);
+        report_running_system(
+            deriv.name,
+            deriv.branch,
+            Some(ApplyOutcome::Applied),
+            Some(deriv.storeHash),
+        );
+        if reboot::warn_if_required() && reboot_if_needed {
+            println!("INFO: rebooting");
+            run_privileged(&["systemctl", "reboot"], Some(password));
+        }
+    }
+}
+
+/// Copy the closure of `hash` from the fastest of the configured substituters, if it
+/// isn't in the local store already.
+fn copy_closure(
+    substituters: &[String],
+    hash: &str,
+    estimate: Option<&Result<narinfo::Missing, String>>,
+) -> bool {
+    if store_path::is_valid(hash) {
+        println!("INFO: {} is already in the local store", hash);
+        return true;
+    }
+    substituter::copy_from(substituters, hash, estimate)
+}
+
+/// Switch the system profile to `hash` with `gurl-apply-helper`.
+fn run_apply_helper(hash: &str, password: Option<String>) -> bool {
+    match run_privileged(&["gurl-apply-helper", hash], password) {
+        Some(exit_status) => {
+            if !exit_status.success() {
                 println!("ERROR: {}", "Failed during closure install!".red())
             }
+            exit_status.success()
+        }
+        None => {
+            println!("ERROR: {}", "Failed to start gurl-apply-helper!".red());
+            false
+        }
+    }
+}
+
+/// Run `args` through `sudo -S` with the given password, or directly when there is none
+/// (which only works as root).
+fn run_privileged(args: &[&str], password: Option<String>) -> Option<ExitStatus> {
+    let mut cmd = match password {
+        Some(_) => Command::new("sudo")
+            .arg("-S")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn(),
+        None => Command::new(args[0])
+            .args(&args[1..])
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn(),
+    }
+    .ok()?;
+    if let Some(password) = password {
+        let mut stdin = cmd.stdin.take().expect("Failed to open stdin");
+        std::thread::spawn(move || stdin.write_all(password.as_bytes()));
+    }
+    cmd.wait().ok()
+}
+
+fn is_root() -> bool {
+    Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|x| x.stdout.trim_ascii() == b"0")
+}
+
+/// Replace the `$HOSTNAME` placeholder default with the actual hostname.
+fn resolve_name(name: String) -> String {
+    if name != "$HOSTNAME" {
+        return name;
+    }
+    String::from_utf8_lossy(
+        Command::new("hostname")
+            .output()
+            .expect("Could not run `hostname`(unix? command), consider adding --name manually.")
+            .stdout
+            .trim_ascii_end(),
+    )
+    .into_owned()
+}
+
+fn handle_deriv_report(name: String, branch: String) {
+    if !report_running_system(name, branch, None, None) {
+        std::process::exit(1);
+    }
+}
+
+/// Tell the server what this host runs. Best effort, a failure is only a warning.
+/// Returns whether the report was sent.
+fn report_running_system(
+    name: String,
+    branch: String,
+    outcome: Option<ApplyOutcome>,
+    target: Option<String>,
+) -> bool {
+    let current_system = match fs::read_link("/run/current-system") {
+        Ok(x) => x,
+        Err(x) => {
+            println!(
+                "WARN: {}",
+                format!(
+                    "not reporting, getting the current system's store hash: {:?}",
+                    x
+                )
+                .yellow()
+            );
+            return false;
+        }
+    };
+    let date_switched = fs::symlink_metadata("/run/current-system")
+        .and_then(|x| x.modified())
+        .ok()
+        .map(DateTime::<Local>::from);
+
+    let report = Report {
+        name: resolve_name(name),
+        host: resolve_name("$HOSTNAME".to_owned()),
+        branch,
+        storeHash: current_system.to_string_lossy().into_owned(),
+        date_switched,
+        date_reported: Some(Local::now()),
+        outcome,
+        target,
+        reboot_required: !reboot::changed_components().is_empty(),
+    };
+    match DB::report(&report) {
+        Ok(res) if res.status.success() => {
+            println!(
+                "INFO: reported {} as running on {}",
+                report.storeHash,
+                report.name.green()
+            );
+            true
+        }
+        Ok(res) => {
+            println!(
+                "WARN: {}; {} {}\n\t{}",
+                "failed to report running system".yellow(),
+                res.status.status_code,
+                res.status.status_message,
+                res.body
+            );
+            false
+        }
+        Err(x) => {
+            println!(
+                "WARN: {}",
+                format!("failed to report running system: {}", x).yellow()
+            );
+            false
         }
-        Err(x) => println!("ERROR: {}", "Failed to start gurl-apply-helper!".red()),
     }
 }
 
@@ -726,17 +1511,17 @@ fn sudo_password_getter() -> Option<String> {
                     "ERROR: {}",
                     "Bad password for sudo. Sudo check failed.".red()
                 );
-                return None;
+                None
             } else {
-                return Some(password);
+                Some(password)
             }
         }
-        Err(x) => {
+        Err(_x) => {
             println!(
                 "ERROR: {}",
                 "Error during checking exit code of sudo echo checker".red()
             );
-            return None;
+            None
         }
     }
 }
@@ -767,20 +1552,30 @@ fn handle_deriv_reapply() {
                 );
             } else {
                 println!("ERROR: {}", "Failed to run switch-to-configuration!".red());
-                return;
             }
         }
-        Err(x) => {
+        Err(_x) => {
             println!(
                 "ERROR: {}",
                 "Failed to start sudo switch-to-configuration".red()
             );
-            return;
         }
     }
 }
 
-fn handle_deriv_rollback() {
+fn handle_deriv_rollback(to: Option<u32>) {
+    let generation = to.map(|x| x.to_string());
+    if let Some(to) = to {
+        if !generations::list().iter().any(|x| x.number == to) {
+            print_exit(
+                &format!(
+                    "ERROR: {}",
+                    format!("there is no generation {} of the system profile", to).red()
+                ),
+                1,
+            );
+        }
+    }
     let password = sudo_password_getter().expect("Failed to get sudo password");
 
     let mut cmd = Command::new("sudo")
@@ -789,8 +1584,11 @@ fn handle_deriv_rollback() {
             "nix-env",
             "--profile",
             "/nix/var/nix/profiles/system",
-            "--rollback",
         ])
+        .args(match &generation {
+            Some(x) => vec!["--switch-generation", x.as_str()],
+            None => vec!["--rollback"],
+        })
         .stdin(Stdio::piped())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
@@ -803,7 +1601,7 @@ fn handle_deriv_rollback() {
     match status {
         Ok(exit_status) => {
             if exit_status.success() {
-                println!("INFO: {}", "Successfully rolled back profile!");
+                println!("INFO: Successfully rolled back profile!");
             } else {
                 println!(
                     "ERROR: {}",
@@ -812,7 +1610,7 @@ fn handle_deriv_rollback() {
                 return;
             }
         }
-        Err(x) => {
+        Err(_x) => {
             println!("ERROR: {}", "Failed to start sudo nix-env".red());
             return;
         }
@@ -846,15 +1644,13 @@ fn handle_deriv_rollback() {
                     "Failed to apply(switch) back to the old profile with switch-to-configuration!"
                         .red()
                 );
-                return;
             }
         }
-        Err(x) => {
+        Err(_x) => {
             println!(
                 "ERROR: {}",
                 "Failed to start sudo switch-to-configuration".red()
             );
-            return;
         }
     }
 }
@@ -871,17 +1667,149 @@ impl DB {
         .ok()
     }
 
-    pub fn delete(name: &String, branch: &String) -> HttpResponse {
+    /// The entry of `name` on `branch`, `Ok(None)` only when the server says there is none.
+    pub fn get_entry(name: &str, branch: &str) -> Result<Option<Deriv>, String> {
+        let payload = Deriv {
+            date_added: None,
+            force: None,
+            id: None,
+            branch: branch.to_owned(),
+            storeHash: "".to_owned(),
+            name: name.to_owned(),
+            provenance: Default::default(),
+            signature: None,
+        };
+        let json_payload =
+            serde_json::to_string(&payload).expect("Failed to serialize payload to json.");
+
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .get(format!("{PUB_HOST}/derivations/"))
+            .body(json_payload)
+            .send()
+            .map_err(|x| format!("getting the entry of {}: {}", name, x))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = response
+            .text()
+            .map_err(|x| format!("getting the entry of {}: {}", name, x))?;
+        if !status.is_success() {
+            return Err(format!(
+                "getting the entry of {}: {}; {}",
+                name,
+                status,
+                body.trim()
+            ));
+        }
+        if body.trim().is_empty() || body.trim() == "null" {
+            return Ok(None);
+        }
+        serde_json::from_str(&body)
+            .map(Some)
+            .map_err(|x| format!("parsing the entry of {}: {}", name, x))
+    }
+
+    pub fn get_reports() -> Option<Vec<Report>> {
+        serde_json::from_str(
+            &reqwest::blocking::get(format!("{PUB_HOST}/reports"))
+                .ok()?
+                .text()
+                .ok()?,
+        )
+        .ok()
+    }
+
+    pub fn report(report: &Report) -> Result<HttpResponse, String> {
+        make_req(
+            "POST /reports",
+            Some(serde_json::to_string(report).unwrap().as_str()),
+        )
+    }
+
+    pub fn get_policies() -> Option<Vec<policy::Policy>> {
+        serde_json::from_str(
+            &reqwest::blocking::get(format!("{PUB_HOST}/policies"))
+                .ok()?
+                .text()
+                .ok()?,
+        )
+        .ok()
+    }
+
+    pub fn set_policy(policy: &policy::Policy) -> HttpResponse {
+        make_req(
+            "POST /policies",
+            Some(serde_json::to_string(policy).unwrap().as_str()),
+        )
+        .unwrap()
+    }
+
+    pub fn get_rollouts() -> Option<Vec<rollout::Rollout>> {
+        serde_json::from_str(
+            &reqwest::blocking::get(format!("{PUB_HOST}/rollouts"))
+                .ok()?
+                .text()
+                .ok()?,
+        )
+        .ok()
+    }
+
+    pub fn save_rollout(rollout: &rollout::Rollout) -> HttpResponse {
+        make_req(
+            "POST /rollouts",
+            Some(serde_json::to_string(rollout).unwrap().as_str()),
+        )
+        .unwrap()
+    }
+
+    pub fn get_groups() -> Option<Vec<group::Group>> {
+        serde_json::from_str(
+            &reqwest::blocking::get(format!("{PUB_HOST}/groups"))
+                .ok()?
+                .text()
+                .ok()?,
+        )
+        .ok()
+    }
+
+    pub fn set_group(group: &group::Group) -> HttpResponse {
+        make_req(
+            "POST /groups",
+            Some(serde_json::to_string(group).unwrap().as_str()),
+        )
+        .unwrap()
+    }
+
+    pub fn delete_group(name: &str) -> HttpResponse {
+        make_req(
+            "DELETE /groups",
+            Some(
+                serde_json::to_string(&group::Group {
+                    name: name.to_owned(),
+                    hosts: Vec::new(),
+                })
+                .unwrap()
+                .as_str(),
+            ),
+        )
+        .unwrap()
+    }
+
+    pub fn delete(name: &str, branch: &str) -> HttpResponse {
         make_req(
             "DELETE /derivations/",
             Some(
                 serde_json::to_string(&Deriv {
                     id: None,
-                    name: name.clone(),
+                    name: name.to_owned(),
                     storeHash: "".to_owned(),
-                    branch: branch.clone(),
+                    branch: branch.to_owned(),
                     force: None,
                     date_added: None,
+                    provenance: Default::default(),
+                    signature: None,
                 })
                 .unwrap()
                 .as_str(),
@@ -912,8 +1840,8 @@ impl HttpStatus {
         let status_message = strs
             .map(|str| {
                 let mut str = str.to_owned();
-                str.push_str(" ");
-                return str;
+                str.push(' ');
+                str
             })
             .collect::<String>()
             .trim()
@@ -0,0 +1,252 @@
+use std::{collections::HashSet, path::Path, sync::OnceLock, thread, time::Duration};
+
+use crate::store_path::{StorePath, STORE_DIR};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most narinfos fetched at the same time while walking a closure.
+const MAX_PARALLEL_FETCHES: usize = 16;
+
+/// One client for all narinfo fetches, so connections to a cache get reused.
+fn client() -> &'static reqwest::blocking::Client {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::blocking::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("Failed to build the http client")
+    })
+}
+
+/// What a binary cache knows about one store path, from its `<hash>.narinfo`.
+#[derive(Debug, Clone)]
+pub struct NarInfo {
+    pub store_path: String,
+    /// Where the nar is, relative to the cache.
+    pub url: String,
+    pub compression: String,
+    pub nar_hash: String,
+    /// Unpacked size in bytes.
+    pub nar_size: u64,
+    /// Compressed size in bytes, not every cache gives it.
+    pub file_size: Option<u64>,
+    /// Base names(`<hash>-<name>`) of the paths this one depends on.
+    pub references: Vec<String>,
+    pub sigs: Vec<String>,
+}
+
+impl NarInfo {
+    pub fn parse(text: &str) -> Result<NarInfo, String> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = "bzip2".to_owned();
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut file_size = None;
+        let mut references = Vec::new();
+        let mut sigs = Vec::new();
+        let parse_size = |key: &str, x: &str| {
+            x.parse::<u64>()
+                .map_err(|_| format!("invalid {} \"{}\" in narinfo", key, x))
+        };
+        for line in text.lines().filter(|x| !x.is_empty()) {
+            let (key, value) = line
+                .split_once(": ")
+                .ok_or(format!("invalid narinfo line \"{}\"", line))?;
+            match key {
+                "StorePath" => store_path = Some(value.to_owned()),
+                "URL" => url = Some(value.to_owned()),
+                "Compression" => compression = value.to_owned(),
+                "NarHash" => nar_hash = Some(value.to_owned()),
+                "NarSize" => nar_size = Some(parse_size(key, value)?),
+                "FileSize" => file_size = Some(parse_size(key, value)?),
+                "References" => {
+                    references = value.split_whitespace().map(|x| x.to_owned()).collect()
+                }
+                "Sig" => sigs.push(value.to_owned()),
+                _ => {}
+            }
+        }
+        let missing = |key: &str| format!("narinfo has no {}", key);
+        Ok(NarInfo {
+            store_path: store_path.ok_or(missing("StorePath"))?,
+            url: url.ok_or(missing("URL"))?,
+            compression,
+            nar_hash: nar_hash.ok_or(missing("NarHash"))?,
+            nar_size: nar_size.ok_or(missing("NarSize"))?,
+            file_size,
+            references,
+            sigs,
+        })
+    }
+
+    /// The narinfo of the path with `hash` on `cache`, `None` if the cache doesn't have it.
+    pub fn fetch(cache: &str, hash: &str) -> Result<Option<NarInfo>, String> {
+        let res = client()
+            .get(format!("{}/{}.narinfo", cache.trim_end_matches('/'), hash))
+            .send()
+            .map_err(|x| format!("fetching narinfo from {}: {}", cache, x))?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(format!("{} answered {} for a narinfo", cache, res.status()));
+        }
+        let text = res
+            .text()
+            .map_err(|x| format!("reading narinfo from {}: {}", cache, x))?;
+        let info = NarInfo::parse(&text)?;
+        if StorePath::parse(&info.store_path)?.hash != hash {
+            return Err(format!(
+                "{} answered with {} for {}",
+                cache, info.store_path, hash
+            ));
+        }
+        Ok(Some(info))
+    }
+
+    /// The first of the http `caches` that has `path`, with its narinfo.
+    pub fn find(caches: &[String], path: &str) -> Option<(String, NarInfo)> {
+        let hash = StorePath::parse(path).ok()?.hash;
+        caches.iter().filter(|x| is_http(x)).find_map(|cache| {
+            NarInfo::fetch(cache, &hash)
+                .ok()
+                .flatten()
+                .map(|x| (cache.clone(), x))
+        })
+    }
+
+    pub fn print(&self, cache: &str) {
+        println!("\tcache: {}", cache);
+        println!(
+            "\tnar: {} ({}, {})",
+            self.url,
+            self.compression,
+            human_size(self.download_size())
+        );
+        println!("\tnar hash: {}", self.nar_hash);
+        let keys: Vec<&str> = self
+            .sigs
+            .iter()
+            .filter_map(|x| x.split_once(':').map(|x| x.0))
+            .collect();
+        println!("\tsigned by: {}", keys.join(", "));
+    }
+
+    /// Bytes to download, the compressed size when known.
+    pub fn download_size(&self) -> u64 {
+        self.file_size.unwrap_or(self.nar_size)
+    }
+}
+
+/// Whether `uri` is a binary cache served over http, which has `<hash>.narinfo` files.
+pub fn is_http(uri: &str) -> bool {
+    uri.starts_with("http://") || uri.starts_with("https://")
+}
+
+/// The part of the closure of a path that isn't in the local store yet.
+#[derive(Debug, Default)]
+pub struct Missing {
+    pub paths: usize,
+    pub download_size: u64,
+    pub nar_size: u64,
+}
+
+/// Walk the references of `path` on `cache`, summing up everything the local store lacks.
+/// Each level of the closure is fetched in parallel, `MAX_PARALLEL_FETCHES` at a time.
+pub fn missing_closure(cache: &str, path: &str) -> Result<Missing, String> {
+    let root = StorePath::parse(path)?;
+    let mut seen = HashSet::from([format!("{}-{}", root.hash, root.name)]);
+    let mut todo = vec![root.hash];
+    let mut missing = Missing::default();
+    while !todo.is_empty() {
+        let mut next = Vec::new();
+        for chunk in todo.chunks(MAX_PARALLEL_FETCHES) {
+            let infos = thread::scope(|scope| {
+                let fetches: Vec<_> = chunk
+                    .iter()
+                    .map(|hash| scope.spawn(move || NarInfo::fetch(cache, hash)))
+                    .collect();
+                fetches
+                    .into_iter()
+                    .zip(chunk)
+                    .map(|(fetch, hash)| {
+                        fetch
+                            .join()
+                            .unwrap_or_else(|_| {
+                                Err(format!("fetching the narinfo of {} panicked", hash))
+                            })?
+                            .ok_or(format!("{} doesn't have {} of the closure", cache, hash))
+                    })
+                    .collect::<Result<Vec<NarInfo>, String>>()
+            })?;
+            for info in infos {
+                missing.paths += 1;
+                missing.download_size += info.download_size();
+                missing.nar_size += info.nar_size;
+                for reference in info.references {
+                    if seen.contains(&reference) {
+                        continue;
+                    }
+                    if !Path::new(STORE_DIR).join(&reference).exists() {
+                        if let Some((hash, _)) = reference.split_once('-') {
+                            next.push(hash.to_owned());
+                        }
+                    }
+                    seen.insert(reference);
+                }
+            }
+        }
+        todo = next;
+    }
+    Ok(missing)
+}
+
+/// What the local store lacks of the closure of `path`, walked on the first http cache
+/// that has it.
+pub fn estimate(caches: &[String], path: &str) -> Result<Missing, String> {
+    let (cache, _) = NarInfo::find(caches, path)
+        .ok_or("no http substituter has the closure to tell its size".to_owned())?;
+    missing_closure(&cache, path)
+}
+
+/// For each of `paths` whether any of the http `caches` has it, asking all of them at once.
+pub fn substitutable(caches: &[String], paths: &[&str]) -> Vec<bool> {
+    let caches: Vec<&String> = caches.iter().filter(|x| is_http(x)).collect();
+    thread::scope(|scope| {
+        let checks: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let caches = &caches;
+                scope.spawn(move || {
+                    let hash = match StorePath::parse(path) {
+                        Ok(x) => x.hash,
+                        Err(_) => return false,
+                    };
+                    caches
+                        .iter()
+                        .any(|cache| matches!(NarInfo::fetch(cache, &hash), Ok(Some(_))))
+                })
+            })
+            .collect();
+        checks
+            .into_iter()
+            .map(|x| x.join().unwrap_or(false))
+            .collect()
+    })
+}
+
+/// `1.5 MiB` and such.
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
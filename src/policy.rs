@@ -0,0 +1,172 @@
+use std::io::Write;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use colored::Colorize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{config::MaintenanceWindow, print_exit, HttpResponse, DB};
+
+/// Per-host rules stored on the server, respected by `deriv apply` and `gurl agent`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Policy {
+    pub name: String,
+    /// Don't switch this host to anything(except `pin`).
+    #[serde(default)]
+    pub hold: bool,
+    /// The hold ends on its own after this date.
+    pub hold_until: Option<DateTime<Local>>,
+    pub hold_message: Option<String>,
+    /// While held, keep the host on this store path instead of its current system.
+    pub pin: Option<String>,
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+}
+
+impl Policy {
+    /// The policy of `name`, a default one if it has none. Fails if the policies can't
+    /// be fetched, as a default would drop a hold.
+    pub fn get(name: &str) -> Result<Policy, String> {
+        let policies = DB::get_policies().ok_or("failed to get host policies".to_owned())?;
+        Ok(policies
+            .into_iter()
+            .find(|x| x.name == name)
+            .unwrap_or(Policy {
+                name: name.to_owned(),
+                ..Default::default()
+            }))
+    }
+
+    /// Like `get`, but exits on failure.
+    fn get_or_exit(name: &str) -> Policy {
+        Policy::get(name).unwrap_or_else(|x| print_exit(&format!("ERROR: {}", x.red()), 1))
+    }
+
+    pub fn is_held(&self, now: DateTime<Local>) -> bool {
+        self.hold && self.hold_until.is_none_or(|until| now < until)
+    }
+
+    pub fn in_window(&self, now: DateTime<Local>) -> bool {
+        self.maintenance_windows.is_empty()
+            || self.maintenance_windows.iter().any(|x| x.contains(now))
+    }
+
+    /// Short description of why this host may not switch right now, if that's the case.
+    pub fn blocker(&self, now: DateTime<Local>) -> Option<String> {
+        if self.is_held(now) {
+            let mut reason = format!("{} is on hold", self.name);
+            if let Some(until) = self.hold_until {
+                reason.push_str(&format!(" until {}", until.format("%Y-%m-%d %H:%M")));
+            }
+            if let Some(pin) = &self.pin {
+                reason.push_str(&format!(", pinned to {}", pin));
+            }
+            if let Some(message) = &self.hold_message {
+                reason.push_str(&format!(" ({})", message));
+            }
+            return Some(reason);
+        }
+        if !self.in_window(now) {
+            return Some(format!(
+                "{} is outside of its maintenance windows",
+                self.name
+            ));
+        }
+        None
+    }
+}
+
+/// Ask the user to type the host name before ignoring its policy.
+pub fn confirm_ignore(name: &str) -> bool {
+    print!(
+        "{} Type the host name({}) to continue anyway: ",
+        "WARN:".yellow(),
+        name
+    );
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).is_err() {
+        return false;
+    }
+    line.trim() == name
+}
+
+/// Accepts `2025-06-01`, `2025-06-01 18:00` or RFC 3339.
+fn parse_date(str: &str) -> Option<DateTime<Local>> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(str) {
+        return Some(x.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(str, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(str, "%Y-%m-%d")
+                .ok()
+                .and_then(|x| x.and_hms_opt(0, 0, 0))
+        })?;
+    Local.from_local_datetime(&naive).single()
+}
+
+fn print_res(res: HttpResponse, name: &str) {
+    if res.status.success() {
+        println!("{}: {}", name, res.body.green());
+    } else {
+        print_exit(
+            &format!(
+                "ERROR: {}; {} {}\n\t{}",
+                "failed to update policy".red(),
+                res.status.status_code,
+                res.status.status_message,
+                res.body
+            ),
+            1,
+        );
+    }
+}
+
+pub fn handle_deriv_hold(
+    name: String,
+    until: Option<String>,
+    pin: Option<String>,
+    message: Option<String>,
+) {
+    let hold_until = until.map(|x| match parse_date(&x) {
+        Some(date) => date,
+        None => {
+            println!(
+                "ERROR: {}",
+                format!("could not parse date \"{}\", try YYYY-MM-DD [HH:MM]", x).red()
+            );
+            std::process::exit(1);
+        }
+    });
+    let mut policy = Policy::get_or_exit(&name);
+    policy.hold = true;
+    policy.hold_until = hold_until;
+    policy.pin = pin;
+    policy.hold_message = message;
+    print_res(DB::set_policy(&policy), &name);
+}
+
+pub fn handle_deriv_unhold(name: String) {
+    let mut policy = Policy::get_or_exit(&name);
+    if !policy.hold {
+        println!("INFO: {} is not on hold", name);
+    }
+    policy.hold = false;
+    policy.hold_until = None;
+    policy.pin = None;
+    policy.hold_message = None;
+    print_res(DB::set_policy(&policy), &name);
+}
+
+pub fn handle_deriv_window(name: String, windows: Vec<String>) {
+    let mut parsed = Vec::new();
+    for window in windows {
+        match window.parse::<MaintenanceWindow>() {
+            Ok(x) => parsed.push(x),
+            Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+        }
+    }
+    let mut policy = Policy::get_or_exit(&name);
+    policy.maintenance_windows = parsed;
+    print_res(DB::set_policy(&policy), &name);
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use colored::Colorize;
+
+use crate::{
+    narinfo::{human_size, Missing, NarInfo},
+    Deriv, DB,
+};
+
+/// Processes that hold the nix store or the system profile for a while, as the executable
+/// name and arguments it has to be run with.
+const BUSY_COMMANDS: [(&str, &[&str]); 4] = [
+    ("switch-to-configuration", &[]),
+    ("nix-collect-garbage", &[]),
+    ("nix-store", &["--gc"]),
+    ("nix", &["store", "gc"]),
+];
+
+/// Interpreters whose first argument that isn't an option is the script that actually runs,
+/// with their options that take the next argument as a value(`perl -I lib`, `bash -o pipefail`).
+/// Perl's `-x` only takes an attached directory and bash's is a flag, so neither is here.
+const INTERPRETERS: [(&str, &[&str]); 3] = [
+    ("perl", &["-I"]),
+    ("bash", &["-o", "-O", "+o", "+O"]),
+    ("sh", &["-o", "+o"]),
+];
+
+/// Whether `argv` runs `executable` with all of `args`.
+fn is_command(argv: &[&str], executable: &str, args: &[&str]) -> bool {
+    let name = |x: &str| x.rsplit('/').next().unwrap_or(x).to_owned();
+    let mut argv = argv.iter();
+    let mut program = argv.next().map(|x| name(x)).unwrap_or_default();
+    if let Some((_, with_value)) = INTERPRETERS.iter().find(|x| x.0 == program) {
+        program = loop {
+            match argv.next() {
+                Some(&"--") => break argv.next().map(|x| name(x)).unwrap_or_default(),
+                Some(option) if option.starts_with(['-', '+']) => {
+                    if with_value.contains(option) {
+                        argv.next();
+                    }
+                }
+                Some(script) => break name(script),
+                None => break String::new(),
+            }
+        };
+    }
+    let rest: Vec<String> = argv.map(|x| name(x)).collect();
+    program == executable && args.iter().all(|x| rest.iter().any(|y| y == x))
+}
+
+/// Outcome of one check. Something that couldn't be checked doesn't stop the apply.
+enum Check {
+    Pass(String),
+    Unknown(String),
+    Fail(String),
+}
+
+/// Free bytes on the filesystem of `/nix`.
+pub fn free_space() -> Option<u64> {
+    let out = Command::new("df")
+        .args(["--output=avail", "-B1", "/nix"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .nth(1)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// `estimate` is `None` when the closure is already in the local store.
+fn check_space(estimate: Option<&Result<Missing, String>>) -> Check {
+    let missing = match estimate {
+        None => return Check::Pass("closure is already in the local store".to_owned()),
+        Some(Ok(x)) => x,
+        Some(Err(x)) => return Check::Unknown(x.clone()),
+    };
+    let Some(free) = free_space() else {
+        return Check::Unknown("could not get the free space on /nix".to_owned());
+    };
+    let needed = format!(
+        "{} needed for {} paths, {} free on /nix",
+        human_size(missing.nar_size),
+        missing.paths,
+        human_size(free)
+    );
+    if missing.nar_size > free {
+        Check::Fail(needed)
+    } else {
+        Check::Pass(needed)
+    }
+}
+
+/// The `system` of the toplevel, like `x86_64-linux`. Read from the local store or a
+/// substituter, where the toplevel's nar is small.
+fn toplevel_system(substituters: &[String], path: &str) -> Result<String, String> {
+    let file = format!("{}/system", path);
+    if Path::new(path).exists() {
+        return fs::read_to_string(&file)
+            .map(|x| x.trim().to_owned())
+            .map_err(|x| format!("{}: {}", file, x));
+    }
+    let (cache, _) = NarInfo::find(substituters, path)
+        .ok_or("no http substituter has the toplevel to read its system from".to_owned())?;
+    let out = Command::new("nix")
+        .args(["store", "cat", "--store", &cache, &file])
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|x| format!("could not run `nix store cat`: {}", x))?;
+    if !out.status.success() {
+        return Err(format!("could not read {} from {}", file, cache));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+fn check_arch(substituters: &[String], path: &str) -> Check {
+    let local = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    let system = match toplevel_system(substituters, path) {
+        Ok(x) => x,
+        Err(x) => return Check::Unknown(x),
+    };
+    if system == local {
+        Check::Pass(format!("built for {}", system))
+    } else {
+        Check::Fail(format!("built for {}, but this is {}", system, local))
+    }
+}
+
+fn check_host(deriv: &Deriv, host: &str) -> Check {
+    if deriv.name == host {
+        return Check::Pass(format!("entry is for {}", host));
+    }
+    let Some(groups) = DB::get_groups() else {
+        return Check::Unknown(format!(
+            "entry is for {}, and failed to get its groups",
+            deriv.name
+        ));
+    };
+    let in_group = groups
+        .into_iter()
+        .any(|x| x.name == deriv.name && x.hosts.iter().any(|x| x == host));
+    if in_group {
+        Check::Pass(format!("{} is in the group {}", host, deriv.name))
+    } else {
+        Check::Fail(format!("entry is for {}, but this is {}", deriv.name, host))
+    }
+}
+
+/// A running activation or garbage collection, which would block or race the apply.
+fn check_busy() -> Check {
+    let entries = match fs::read_dir("/proc") {
+        Ok(x) => x,
+        Err(x) => return Check::Unknown(format!("/proc: {}", x)),
+    };
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if pid.parse::<u32>().is_err() {
+            continue;
+        }
+        let cmdline = match fs::read(entry.path().join("cmdline")) {
+            Ok(x) => String::from_utf8_lossy(&x).into_owned(),
+            Err(_) => continue,
+        };
+        let argv: Vec<&str> = cmdline.split('\0').filter(|x| !x.is_empty()).collect();
+        if BUSY_COMMANDS
+            .iter()
+            .any(|(executable, args)| is_command(&argv, executable, args))
+        {
+            return Check::Fail(format!("already running(pid {}): {}", pid, argv.join(" ")));
+        }
+    }
+    Check::Pass("no other activation or garbage collection running".to_owned())
+}
+
+/// Check what can be checked before copying anything, printing a summary. `estimate` is
+/// what's missing of the closure(see `narinfo::estimate`), `None` if nothing is.
+/// Returns whether all checks passed.
+pub fn run(
+    deriv: &Deriv,
+    host: &str,
+    substituters: &[String],
+    estimate: Option<&Result<Missing, String>>,
+) -> bool {
+    let checks = [
+        ("disk space", check_space(estimate)),
+        ("architecture", check_arch(substituters, &deriv.storeHash)),
+        ("hostname", check_host(deriv, host)),
+        ("nix store", check_busy()),
+    ];
+    println!("INFO: pre-flight checks:");
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Check::Pass(x) => println!("\t{} {}: {}", "ok".green(), name, x),
+            Check::Unknown(x) => println!("\t{} {}: {}", "??".yellow(), name, x),
+            Check::Fail(x) => {
+                println!("\t{} {}: {}", "FAIL".red(), name, x);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreter_options_are_skipped() {
+        let stc =
+            "/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-nixos-system/bin/switch-to-configuration";
+        let matches = |argv: &[&str]| is_command(argv, "switch-to-configuration", &[]);
+        assert!(matches(&[stc, "switch"]));
+        assert!(matches(&["perl", stc, "switch"]));
+        assert!(matches(&["/usr/bin/perl", "-I", "lib", stc, "switch"]));
+        assert!(matches(&["perl", "-Ilib", "-w", stc, "switch"]));
+        assert!(matches(&["bash", "-e", stc]));
+        assert!(matches(&["bash", "-x", "-o", "pipefail", stc]));
+        assert!(matches(&["sh", "--", stc]));
+        assert!(!matches(&["perl", "-I", stc]));
+        assert!(!matches(&["bash", "-c", "echo"]));
+        assert!(!matches(&["vim", stc]));
+    }
+
+    #[test]
+    fn needs_all_args() {
+        assert!(is_command(&["nix-store", "--gc"], "nix-store", &["--gc"]));
+        assert!(is_command(
+            &["bash", "-e", "/bin/nix", "store", "gc"],
+            "nix",
+            &["store", "gc"]
+        ));
+        assert!(!is_command(
+            &["nix-store", "--realise", "/nix/store/x"],
+            "nix-store",
+            &["--gc"]
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use serde_json::Value;
+
+use crate::narinfo::human_size;
+
+/// Set by `--verbose`, nix then prints its own logs instead of the progress bar.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+// Activity and result types of nix's `--log-format internal-json`
+const ACT_COPY_PATH: u64 = 100;
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const RES_PROGRESS: u64 = 105;
+
+const BAR_WIDTH: usize = 20;
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Progress {
+    /// Activity id -> its type.
+    activities: HashMap<u64, u64>,
+    /// Done and expected bytes of each running or finished path copy.
+    copy_bytes: HashMap<u64, (u64, u64)>,
+    /// Same for downloads. A path substituted from a binary cache shows up as both a copy
+    /// and a download of the same bytes.
+    transfer_bytes: HashMap<u64, (u64, u64)>,
+    paths_done: u64,
+    paths_expected: u64,
+}
+
+impl Progress {
+    /// Feed one `@nix {...}` line, returning a message nix wants shown.
+    fn update(&mut self, json: &Value) -> Option<String> {
+        let id = json["id"].as_u64().unwrap_or_default();
+        match json["action"].as_str()? {
+            "start" => {
+                self.activities.insert(id, json["type"].as_u64()?);
+            }
+            "result" if json["type"].as_u64() == Some(RES_PROGRESS) => {
+                let field = |i: usize| json["fields"][i].as_u64().unwrap_or_default();
+                match self.activities.get(&id) {
+                    Some(&ACT_COPY_PATHS) => {
+                        self.paths_done = field(0);
+                        self.paths_expected = field(1);
+                    }
+                    Some(&ACT_COPY_PATH) => {
+                        self.copy_bytes.insert(id, (field(0), field(1)));
+                    }
+                    Some(&ACT_FILE_TRANSFER) => {
+                        self.transfer_bytes.insert(id, (field(0), field(1)));
+                    }
+                    _ => {}
+                }
+            }
+            // Errors and warnings only, the rest is what --verbose is for
+            "msg" if json["level"].as_u64().is_some_and(|x| x <= 1) => {
+                return json["msg"].as_str().map(|x| x.to_owned());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Done and expected bytes, counting the downloads when copying from a binary cache and
+    /// the path copies otherwise(ssh and local stores), never both.
+    fn bytes(&self) -> (u64, u64) {
+        let bytes = if self.transfer_bytes.is_empty() {
+            &self.copy_bytes
+        } else {
+            &self.transfer_bytes
+        };
+        bytes
+            .values()
+            .fold((0, 0), |acc, x| (acc.0 + x.0, acc.1 + x.1.max(x.0)))
+    }
+
+    fn render(&self, elapsed: Duration) -> String {
+        let (done, expected) = self.bytes();
+        let ratio = if expected == 0 {
+            0.0
+        } else {
+            done as f64 / expected as f64
+        };
+        let filled = (ratio * BAR_WIDTH as f64) as usize;
+        let speed = done as f64 / elapsed.as_secs_f64().max(0.001);
+        let eta = if speed > 0.0 && expected > done {
+            format!("{}s", ((expected - done) as f64 / speed) as u64)
+        } else {
+            "---".to_owned()
+        };
+        format!(
+            "[{}{}] {}/{} paths, {}/{}, {}/s, ETA {}",
+            "#".repeat(filled.min(BAR_WIDTH)),
+            "-".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)),
+            self.paths_done,
+            self.paths_expected,
+            human_size(done),
+            human_size(expected),
+            human_size(speed as u64),
+            eta
+        )
+    }
+}
+
+/// Run a nix command, drawing one progress line from its `internal-json` logs and printing a
+/// summary when done. With `--verbose` nix's own output is shown instead.
+pub fn run_nix(cmd: &mut Command) -> io::Result<ExitStatus> {
+    cmd.stdout(Stdio::inherit());
+    if VERBOSE.load(Ordering::Relaxed) {
+        return cmd.stderr(Stdio::inherit()).status();
+    }
+    let mut child = cmd
+        .args(["--log-format", "internal-json"])
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stderr = child.stderr.take().expect("stderr of nix is piped");
+
+    let interactive = io::stderr().is_terminal();
+    let start = Instant::now();
+    let mut last_draw = start;
+    let mut progress = Progress::default();
+    let clear = |interactive: bool| {
+        if interactive {
+            eprint!("\r\x1b[2K");
+        }
+    };
+    for line in BufReader::new(stderr).lines() {
+        let line = line?;
+        let json = match line.strip_prefix("@nix ") {
+            Some(x) => serde_json::from_str::<Value>(x).unwrap_or_default(),
+            None => {
+                // Not everything nix prints goes through the logger
+                clear(interactive);
+                eprintln!("{}", line);
+                continue;
+            }
+        };
+        if let Some(msg) = progress.update(&json) {
+            clear(interactive);
+            eprintln!("{}", msg);
+        }
+        if interactive && last_draw.elapsed() >= REDRAW_EVERY {
+            last_draw = Instant::now();
+            eprint!("\r\x1b[2K{}", progress.render(start.elapsed()));
+            let _ = io::stderr().flush();
+        }
+    }
+    let status = child.wait()?;
+    clear(interactive);
+
+    let (done, _) = progress.bytes();
+    let summary = format!(
+        "{} paths, {} in {:.1}s",
+        progress.paths_done,
+        human_size(done),
+        start.elapsed().as_secs_f64()
+    );
+    if status.success() {
+        println!("INFO: {}", summary);
+    } else {
+        println!("WARN: {}", format!("nix failed after {}", summary).yellow());
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn feed(progress: &mut Progress, lines: &[Value]) {
+        for line in lines {
+            progress.update(line);
+        }
+    }
+
+    #[test]
+    fn copy_bytes_over_ssh() {
+        let mut progress = Progress::default();
+        feed(
+            &mut progress,
+            &[
+                json!({"action": "start", "id": 1, "type": ACT_COPY_PATHS}),
+                json!({"action": "start", "id": 2, "type": ACT_COPY_PATH}),
+                json!({"action": "start", "id": 3, "type": ACT_COPY_PATH}),
+                json!({"action": "result", "id": 1, "type": RES_PROGRESS, "fields": [1, 2, 0, 0]}),
+                json!({"action": "result", "id": 2, "type": RES_PROGRESS, "fields": [100, 100, 0, 0]}),
+                json!({"action": "result", "id": 3, "type": RES_PROGRESS, "fields": [20, 50, 0, 0]}),
+                // A later update of the same path replaces the earlier one
+                json!({"action": "result", "id": 3, "type": RES_PROGRESS, "fields": [30, 50, 0, 0]}),
+            ],
+        );
+        assert_eq!(progress.bytes(), (130, 150));
+        assert_eq!((progress.paths_done, progress.paths_expected), (1, 2));
+    }
+
+    #[test]
+    fn substituted_paths_count_once() {
+        // Copying from a binary cache reports each path as a copy and as a download
+        let mut progress = Progress::default();
+        feed(
+            &mut progress,
+            &[
+                json!({"action": "start", "id": 2, "type": ACT_COPY_PATH}),
+                json!({"action": "start", "id": 3, "type": ACT_FILE_TRANSFER}),
+                json!({"action": "result", "id": 2, "type": RES_PROGRESS, "fields": [40, 80, 0, 0]}),
+                json!({"action": "result", "id": 3, "type": RES_PROGRESS, "fields": [40, 80, 0, 0]}),
+            ],
+        );
+        assert_eq!(progress.bytes(), (40, 80));
+    }
+
+    #[test]
+    fn unknown_expected_size() {
+        // nix sends 0 expected bytes until it knows, done still counts toward the total
+        let mut progress = Progress::default();
+        feed(
+            &mut progress,
+            &[
+                json!({"action": "start", "id": 3, "type": ACT_FILE_TRANSFER}),
+                json!({"action": "result", "id": 3, "type": RES_PROGRESS, "fields": [25, 0, 0, 0]}),
+            ],
+        );
+        assert_eq!(progress.bytes(), (25, 25));
+    }
+
+    #[test]
+    fn shows_only_errors() {
+        let mut progress = Progress::default();
+        assert_eq!(
+            progress.update(&json!({"action": "msg", "level": 0, "msg": "error: oops"})),
+            Some("error: oops".to_owned())
+        );
+        assert_eq!(
+            progress.update(&json!({"action": "msg", "level": 3, "msg": "copying path"})),
+            None
+        );
+    }
+}
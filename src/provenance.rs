@@ -0,0 +1,77 @@
+use std::{path::Path, process::Command};
+
+use colored::{ColoredString, Colorize};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{git, resolve_name};
+
+/// Where an uploaded derivation came from. Every field is optional, entries uploaded
+/// before this existed have none of them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Provenance {
+    pub git_rev: Option<String>,
+    /// Uncommitted changes were in the working tree at upload time.
+    pub git_dirty: Option<bool>,
+    /// `nix hash file` of the flake.lock.
+    pub flake_lock_hash: Option<String>,
+    pub uploader: Option<String>,
+    pub builder: Option<String>,
+    pub message: Option<String>,
+}
+
+fn flake_lock_hash(dir: &Path) -> Option<String> {
+    let lock = dir.join("flake.lock");
+    if !lock.exists() {
+        return None;
+    }
+    let out = Command::new("nix")
+        .args(["hash", "file", "--type", "sha256"])
+        .arg(lock)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(out.stdout.trim_ascii()).into_owned())
+}
+
+impl Provenance {
+    /// Gather what can be found out about the working tree in `dir`.
+    pub fn collect(dir: Option<&Path>, message: Option<String>) -> Provenance {
+        let dir = dir.unwrap_or(Path::new("."));
+        Provenance {
+            git_rev: git::revision(dir),
+            git_dirty: git::is_dirty(dir),
+            flake_lock_hash: flake_lock_hash(dir),
+            uploader: git::config(dir, "user.email").or(std::env::var("USER").ok()),
+            builder: Some(resolve_name("$HOSTNAME".to_owned())),
+            message,
+        }
+    }
+
+    /// Short revision with a `*` when the tree was dirty, like `1a2b3c4*`.
+    pub fn short_rev(&self) -> ColoredString {
+        match &self.git_rev {
+            Some(rev) => {
+                let short = rev.chars().take(7).collect::<String>();
+                if self.git_dirty == Some(true) {
+                    format!("{}*", short).yellow()
+                } else {
+                    short.normal()
+                }
+            }
+            None => "---".normal(),
+        }
+    }
+
+    pub fn print(&self) {
+        let or_unknown = |x: &Option<String>| x.clone().unwrap_or("---".to_owned());
+        println!("\trevision: {}", self.short_rev());
+        println!("\tflake.lock: {}", or_unknown(&self.flake_lock_hash));
+        println!("\tuploader: {}", or_unknown(&self.uploader));
+        println!("\tbuilder: {}", or_unknown(&self.builder));
+        if let Some(message) = &self.message {
+            println!("\tmessage: {}", message);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use colored::Colorize;
+
+use crate::{config::Config, copy_closure, group, print_exit, resolve_name};
+
+/// Where the gc root of a pulled entry lives, `~/.cache/gurl/pulled/<name>`.
+fn gc_root(name: &str) -> PathBuf {
+    std::env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|x| PathBuf::from(x).join(".cache")))
+        .unwrap_or(PathBuf::from("/tmp"))
+        .join("gurl/pulled")
+        .join(name)
+}
+
+/// Keep `path` from being garbage collected until `remove_gc_root(name)`.
+fn add_gc_root(name: &str, path: &str) -> Result<PathBuf, String> {
+    let root = gc_root(name);
+    if let Some(dir) = root.parent() {
+        fs::create_dir_all(dir).map_err(|x| format!("{}: {}", dir.display(), x))?;
+    }
+    let status = Command::new("nix-store")
+        .arg("--add-root")
+        .arg(&root)
+        .args(["--realise", path])
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|x| format!("could not run `nix-store --add-root`: {}", x))?;
+    if !status.success() {
+        return Err(format!("`nix-store --add-root {}` failed", root.display()));
+    }
+    Ok(root)
+}
+
+/// Drop the gc root `deriv pull` made for `name`, if there is one.
+pub fn remove_gc_root(name: &str) {
+    let root = gc_root(name);
+    if root.symlink_metadata().is_ok() {
+        if let Err(x) = fs::remove_file(&root) {
+            println!(
+                "WARN: {}",
+                format!("removing the gc root {}: {}", root.display(), x).yellow()
+            );
+        }
+    }
+}
+
+pub fn handle_deriv_pull(name: String, branch: String) {
+    let name = resolve_name(name);
+    println!("INFO: name set as: {}", name);
+    let deriv = match group::resolve_entry(&name, &branch) {
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+    group::print_follows(&name, &deriv);
+    println!("INFO: pulling {} ({})", deriv.storeHash, deriv.name);
+    if !copy_closure(&Config::load().substituters, &deriv.storeHash, None) {
+        std::process::exit(1);
+    }
+    match add_gc_root(&deriv.name, &deriv.storeHash) {
+        Ok(root) => println!(
+            "INFO: {} (gc root at {}), `gurl deriv apply` will use it",
+            "Closure is ready".green(),
+            root.display()
+        ),
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fs, path::Path};
+
+use colored::Colorize;
+
+/// Parts of a system that only take effect after a reboot.
+const BOOT_COMPONENTS: [&str; 4] = ["kernel", "initrd", "kernel-modules", "systemd"];
+
+/// The parts that differ between the booted and the current system. Empty if nothing
+/// changed or there is no `/run/booted-system`(like in containers).
+pub fn changed_components() -> Vec<&'static str> {
+    let target =
+        |system: &str, component: &str| fs::canonicalize(Path::new(system).join(component)).ok();
+    BOOT_COMPONENTS
+        .into_iter()
+        .filter(|x| {
+            let booted = target("/run/booted-system", x);
+            booted.is_some() && booted != target("/run/current-system", x)
+        })
+        .collect()
+}
+
+/// Warn if the current system needs a reboot, returning whether it does.
+pub fn warn_if_required() -> bool {
+    let changed = changed_components();
+    if !changed.is_empty() {
+        println!(
+            "WARN: {} ({} changed)",
+            "reboot required".yellow(),
+            changed.join(", ")
+        );
+    }
+    !changed.is_empty()
+}
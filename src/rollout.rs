@@ -0,0 +1,443 @@
+use std::{thread, time::Duration};
+
+use chrono::{DateTime, Local};
+use colored::{ColoredString, Colorize};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    handle_date_to_dynamic_info, json_print, make_upload_req, policy::Policy, print_exit,
+    provenance::Provenance, table_print, ApplyOutcome, Deriv, Fonal, OutputFormat, UploadHashAPI,
+    UploadReqError, DB,
+};
+
+/// Seconds between two looks at the host reports while a wave is running.
+const REPORT_POLL_INTERVAL: u64 = 10;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutState {
+    Running,
+    Succeeded,
+    /// Too many hosts failed and the entry was set back to `previous`.
+    Reverted,
+}
+
+impl RolloutState {
+    fn colored(&self) -> ColoredString {
+        match self {
+            RolloutState::Running => "running".yellow(),
+            RolloutState::Succeeded => "succeeded".green(),
+            RolloutState::Reverted => "reverted".red(),
+        }
+    }
+}
+
+/// Moving the entry `name` on `branch` to `storeHash` a few hosts at a time.
+/// Hosts that aren't up yet are put on hold, so their agents keep the old system.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct Rollout {
+    pub name: String,
+    pub branch: String,
+    pub storeHash: String,
+    pub previous: Option<String>,
+    pub hosts: Vec<String>,
+    pub canaries: usize,
+    pub batch_size: usize,
+    pub max_failures: usize,
+    pub state: RolloutState,
+    pub done: Vec<String>,
+    pub failed: Vec<String>,
+    pub date_started: Option<DateTime<Local>>,
+}
+
+impl Rollout {
+    /// Canaries first, then `batch_size` hosts per wave.
+    fn waves(&self) -> Vec<Vec<String>> {
+        let canaries = self.canaries.min(self.hosts.len());
+        let mut waves = vec![self.hosts[..canaries].to_vec()];
+        waves.extend(
+            self.hosts[canaries..]
+                .chunks(self.batch_size.max(1))
+                .map(|x| x.to_vec()),
+        );
+        waves.retain(|x| !x.is_empty());
+        waves
+    }
+
+    fn save(&self) {
+        let res = DB::save_rollout(self);
+        if !res.status.success() {
+            println!(
+                "WARN: {}; {} {}",
+                "failed to save rollout state on the server".yellow(),
+                res.status.status_code,
+                res.status.status_message
+            );
+        }
+    }
+}
+
+fn set_entry(
+    name: &str,
+    branch: &str,
+    hash: &str,
+    provenance: Provenance,
+) -> Result<String, UploadReqError> {
+    let payload = UploadHashAPI::new(name, hash, branch.to_owned(), Some(true), provenance);
+    make_upload_req(serde_json::to_string(&payload).expect("Failed to serialize payload to json."))
+}
+
+fn set_hold(host: &str, hold: bool, message: Option<String>) {
+    // Leaves the holds made so far, which `gurl deriv unhold` releases
+    let mut policy = match Policy::get(host) {
+        Ok(x) => x,
+        Err(x) => print_exit(
+            &format!(
+                "ERROR: {}",
+                format!("{}, not changing the hold of {}", x, host).red()
+            ),
+            1,
+        ),
+    };
+    policy.hold = hold;
+    policy.hold_until = None;
+    policy.pin = None;
+    policy.hold_message = message;
+    let res = DB::set_policy(&policy);
+    if !res.status.success() {
+        println!(
+            "WARN: {} {}: {}",
+            "failed to update the hold of".yellow(),
+            host,
+            res.body
+        );
+    }
+}
+
+/// Wait until every host of the wave runs `hash`, reports a failure or times out. A host
+/// already running `hash`, or whose last report is a failure to apply it(its agent won't
+/// try that again), is settled right away. Returns the hosts that failed.
+fn wait_for_wave(
+    wave: &[String],
+    hash: &str,
+    since: DateTime<Local>,
+    timeout: Duration,
+) -> Vec<String> {
+    let mut pending: Vec<String> = wave.to_vec();
+    let mut failed = Vec::new();
+    loop {
+        let reports = DB::get_reports();
+        match &reports {
+            Some(reports) => pending.retain(|host| {
+                let report = match reports
+                    .iter()
+                    .filter(|x| &x.host == host)
+                    .max_by_key(|x| x.date_reported)
+                {
+                    Some(x) => x,
+                    None => return true,
+                };
+                if report.storeHash == hash {
+                    println!("INFO: {} is up", host.green());
+                    return false;
+                }
+                let about_this = report.target.as_deref() == Some(hash)
+                    || report.date_reported.is_some_and(|date| date > since);
+                match report.outcome {
+                    Some(ApplyOutcome::Applied) | None => true,
+                    Some(outcome) if about_this => {
+                        println!("ERROR: {} {:?}", host.red(), outcome);
+                        failed.push(host.clone());
+                        false
+                    }
+                    Some(_) => true,
+                }
+            }),
+            None => println!(
+                "WARN: {}",
+                "Failed to get host reports, trying again".yellow()
+            ),
+        }
+        if pending.is_empty() {
+            return failed;
+        }
+        if (Local::now() - since).to_std().unwrap_or_default() > timeout {
+            // Not the hosts' fault, so no revert either
+            if reports.is_none() {
+                print_exit(
+                    &format!(
+                        "ERROR: {}",
+                        "Could not get host reports, stopping the rollout as it is. \
+                        `gurl deriv unhold` releases the hosts still on hold"
+                            .red()
+                    ),
+                    1,
+                );
+            }
+            for host in &pending {
+                println!("ERROR: {} did not report in time", host.red());
+            }
+            failed.append(&mut pending);
+            return failed;
+        }
+        thread::sleep(Duration::from_secs(REPORT_POLL_INTERVAL));
+    }
+}
+
+/// Where `hash` was uploaded last, so the rollout entry keeps its git rev and message.
+fn source_provenance(hash: &str) -> Provenance {
+    let derivations = match DB::get_all() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get entries".red()), 1),
+    };
+    derivations
+        .into_iter()
+        .filter(|x| x.storeHash == hash)
+        .max_by_key(|x| x.date_added)
+        .map(|x| x.provenance)
+        .unwrap_or_default()
+}
+
+fn revert(rollout: &mut Rollout, previous: Option<&Deriv>, held: &[String]) {
+    println!("INFO: reverting {} on {}", rollout.name, rollout.branch);
+    let res = match previous {
+        Some(previous) => set_entry(
+            &rollout.name,
+            &rollout.branch,
+            &previous.storeHash,
+            previous.provenance.clone(),
+        )
+        .is_ok(),
+        None => DB::delete(&rollout.name, &rollout.branch).status.success(),
+    };
+    if !res {
+        println!(
+            "ERROR: {}",
+            "Failed to set the entry back, do it by hand!".red()
+        );
+    }
+    for host in held {
+        set_hold(host, false, None);
+    }
+    rollout.state = RolloutState::Reverted;
+    rollout.save();
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_rollout_start(
+    name: String,
+    hash: String,
+    branch: String,
+    hosts: Vec<String>,
+    canaries: usize,
+    batch_size: usize,
+    max_failures: usize,
+    timeout: u64,
+) {
+    let now = Local::now();
+    let hosts: Vec<String> = hosts
+        .into_iter()
+        .filter(|host| {
+            let held = match Policy::get(host) {
+                Ok(x) => x.is_held(now),
+                Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+            };
+            if held {
+                println!("WARN: {} is on hold, leaving it out", host.yellow());
+            }
+            !held
+        })
+        .collect();
+    if hosts.is_empty() {
+        print_exit(&format!("ERROR: {}", "No hosts to roll out to".red()), 1);
+    }
+
+    let previous = match DB::get_entry(&name, &branch) {
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+    let provenance = source_provenance(&hash);
+    let mut rollout = Rollout {
+        previous: previous.as_ref().map(|x| x.storeHash.clone()),
+        name,
+        branch,
+        storeHash: hash,
+        hosts,
+        canaries,
+        batch_size,
+        max_failures,
+        state: RolloutState::Running,
+        done: Vec::new(),
+        failed: Vec::new(),
+        date_started: Some(now),
+    };
+
+    let message = format!("rollout of {} on {}", rollout.name, rollout.branch);
+    for host in &rollout.hosts {
+        set_hold(host, true, Some(message.clone()));
+    }
+    println!(
+        "INFO: holding {} hosts until their wave, `gurl deriv unhold` releases them if this gets interrupted",
+        rollout.hosts.len()
+    );
+    match set_entry(
+        &rollout.name,
+        &rollout.branch,
+        &rollout.storeHash,
+        provenance,
+    ) {
+        Ok(x) => println!("{}", x),
+        Err(err) => {
+            for host in &rollout.hosts {
+                set_hold(host, false, None);
+            }
+            match err {
+                UploadReqError::Comment(x) => print_exit(&x, 1),
+                UploadReqError::StoreHashNotFound => print_exit(
+                    &format!(
+                        "ERROR: {}",
+                        "the closure is not on the server, `gurl deriv up` it first".red()
+                    ),
+                    1,
+                ),
+            }
+        }
+    }
+    rollout.save();
+
+    let waves = rollout.waves();
+    for (index, wave) in waves.iter().enumerate() {
+        println!(
+            "INFO: wave {}/{}: {}",
+            index + 1,
+            waves.len(),
+            wave.join(", ")
+        );
+        let since = Local::now();
+        for host in wave {
+            set_hold(host, false, None);
+        }
+        let mut failed = wait_for_wave(
+            wave,
+            &rollout.storeHash,
+            since,
+            Duration::from_secs(timeout),
+        );
+        rollout
+            .done
+            .extend(wave.iter().filter(|x| !failed.contains(x)).cloned());
+        rollout.failed.append(&mut failed);
+        rollout.save();
+
+        if rollout.failed.len() > rollout.max_failures {
+            println!(
+                "ERROR: {}",
+                format!(
+                    "{} hosts failed, more than the allowed {}",
+                    rollout.failed.len(),
+                    rollout.max_failures
+                )
+                .red()
+            );
+            let held: Vec<String> = waves[index + 1..].concat();
+            revert(&mut rollout, previous.as_ref(), &held);
+            std::process::exit(1);
+        }
+    }
+
+    rollout.state = RolloutState::Succeeded;
+    rollout.save();
+    println!(
+        "INFO: {} ({} up, {} failed)",
+        "Rollout finished!".green(),
+        rollout.done.len(),
+        rollout.failed.len()
+    );
+}
+
+pub fn handle_rollout_ls(output: OutputFormat) {
+    let rollouts = match DB::get_rollouts() {
+        Some(x) => x,
+        None => print_exit(&format!("ERROR: {}", "Failed to get rollouts".red()), 1),
+    };
+    if output == OutputFormat::Json {
+        return json_print(&rollouts);
+    }
+
+    let mut table: Vec<Vec<Fonal>> = Vec::new();
+    for rollout in rollouts {
+        table.push(vec![
+            rollout.name.into(),
+            rollout.branch.into(),
+            rollout.state.colored().into(),
+            format!(
+                "{}/{} ({} failed)",
+                rollout.done.len(),
+                rollout.hosts.len(),
+                rollout.failed.len()
+            )
+            .into(),
+            handle_date_to_dynamic_info(rollout.date_started).into(),
+            rollout.storeHash.into(),
+        ]);
+    }
+    table.push(vec![
+        Fonal::String("Name".to_owned()),
+        Fonal::String("Branch".to_owned()),
+        Fonal::String("State".to_owned()),
+        Fonal::String("Hosts".to_owned()),
+        Fonal::String("Started".to_owned()),
+        Fonal::String("Hash".to_owned()),
+    ]);
+
+    table_print::<6>(table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollout(hosts: usize, canaries: usize, batch_size: usize) -> Rollout {
+        Rollout {
+            name: "web".to_owned(),
+            branch: "main".to_owned(),
+            storeHash: "/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-nixos-system".to_owned(),
+            previous: None,
+            hosts: (1..=hosts).map(|x| format!("h{}", x)).collect(),
+            canaries,
+            batch_size,
+            max_failures: 0,
+            state: RolloutState::Running,
+            done: Vec::new(),
+            failed: Vec::new(),
+            date_started: None,
+        }
+    }
+
+    fn sizes(rollout: &Rollout) -> Vec<usize> {
+        rollout.waves().iter().map(|x| x.len()).collect()
+    }
+
+    #[test]
+    fn canaries_then_batches() {
+        let rollout = rollout(7, 1, 3);
+        assert_eq!(sizes(&rollout), [1, 3, 3]);
+        assert_eq!(rollout.waves()[0], ["h1"]);
+        assert_eq!(rollout.waves().concat(), rollout.hosts);
+    }
+
+    #[test]
+    fn last_wave_takes_the_rest() {
+        assert_eq!(sizes(&rollout(6, 1, 2)), [1, 2, 2, 1]);
+        assert_eq!(sizes(&rollout(5, 0, 2)), [2, 2, 1]);
+    }
+
+    #[test]
+    fn no_empty_waves() {
+        // More canaries than hosts, and a batch size of 0 which counts as 1
+        assert_eq!(sizes(&rollout(2, 5, 3)), [2]);
+        assert_eq!(sizes(&rollout(3, 0, 0)), [1, 1, 1]);
+        assert!(rollout(0, 1, 3).waves().is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde_json::Value;
+
+use crate::{config::Publisher, provenance::Provenance, store_path::NIX_BASE32, Deriv};
+
+/// An ed25519 key in nix's `<name>:<base64>` form, like in `trusted-public-keys`.
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    pub fn parse(str: &str) -> Result<PublicKey, String> {
+        let (name, key) = str
+            .split_once(':')
+            .ok_or(format!("key \"{}\" should look like <name>:<base64>", str))?;
+        let key: [u8; 32] = STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|x| x.try_into().ok())
+            .ok_or(format!("key {} is not a base64 ed25519 public key", name))?;
+        Ok(PublicKey {
+            name: name.to_owned(),
+            key: VerifyingKey::from_bytes(&key)
+                .map_err(|x| format!("key {} is invalid: {}", name, x))?,
+        })
+    }
+
+    pub fn parse_all(keys: &[String]) -> Result<Vec<PublicKey>, String> {
+        keys.iter().map(|x| PublicKey::parse(x)).collect()
+    }
+}
+
+/// A secret key as made by `nix key generate-secret`, `<name>:<base64 of secret and public key>`.
+pub struct SecretKey {
+    pub name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    pub fn read(path: &Path) -> Result<SecretKey, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|x| format!("{}: {}", path.display(), x))?;
+        let (name, key) = contents
+            .trim()
+            .split_once(':')
+            .ok_or(format!("{} should contain <name>:<base64>", path.display()))?;
+        let key: [u8; 32] = STANDARD
+            .decode(key)
+            .ok()
+            .filter(|x| x.len() == 64)
+            .and_then(|x| x[..32].try_into().ok())
+            .ok_or(format!("{} is not an ed25519 secret key", path.display()))?;
+        Ok(SecretKey {
+            name: name.to_owned(),
+            key: SigningKey::from_bytes(&key),
+        })
+    }
+
+    /// `<name>:<base64>`, what `verify` takes.
+    pub fn sign(&self, message: &[u8]) -> String {
+        format!(
+            "{}:{}",
+            self.name,
+            STANDARD.encode(self.key.sign(message).to_bytes())
+        )
+    }
+}
+
+/// The name of the trusted key that made `signature`(`<name>:<base64>`) over `message`.
+pub fn verify<'a>(trusted: &'a [PublicKey], signature: &str, message: &[u8]) -> Option<&'a str> {
+    let (name, signature) = signature.split_once(':')?;
+    let signature: [u8; 64] = STANDARD.decode(signature).ok()?.try_into().ok()?;
+    let signature = Signature::from_bytes(&signature);
+    trusted
+        .iter()
+        .filter(|x| x.name == name)
+        .find(|x| x.key.verify_strict(message, &signature).is_ok())
+        .map(|x| x.name.as_str())
+}
+
+/// Nix's own base32, which also goes from the end of the bytes.
+fn nix_base32(bytes: &[u8]) -> String {
+    let chars = NIX_BASE32.as_bytes();
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let high = bytes.get(i + 1).map_or(0, |x| (*x as u16) << (8 - j));
+            let c = ((bytes[i] as u16 >> j) | high) & 0x1f;
+            chars[c as usize] as char
+        })
+        .collect()
+}
+
+/// `sha256:<nix base32>` as signatures use it, from that or the SRI `sha256-<base64>`.
+fn nar_hash(hash: &str) -> Result<String, String> {
+    if hash.starts_with("sha256:") {
+        return Ok(hash.to_owned());
+    }
+    let bytes = hash
+        .strip_prefix("sha256-")
+        .and_then(|x| STANDARD.decode(x).ok())
+        .ok_or(format!("unknown nar hash format {}", hash))?;
+    Ok(format!("sha256:{}", nix_base32(&bytes)))
+}
+
+/// What a store path's signatures are made over.
+pub fn fingerprint(path: &str, nar_hash: &str, nar_size: u64, references: &[String]) -> String {
+    let mut references = references.to_vec();
+    references.sort();
+    format!(
+        "1;{};{};{};{}",
+        path,
+        nar_hash,
+        nar_size,
+        references.join(",")
+    )
+}
+
+/// Check that `path` in the local store is signed by one of `trusted`, returning that key's name.
+pub fn verify_store_path(path: &str, trusted: &[PublicKey]) -> Result<String, String> {
+    let out = Command::new("nix")
+        .args(["path-info", "--json", "--sigs", path])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|x| format!("could not run `nix path-info`: {}", x))?;
+    if !out.status.success() {
+        return Err(format!("`nix path-info {}` failed", path));
+    }
+    let json: Value = serde_json::from_slice(&out.stdout)
+        .map_err(|x| format!("parsing the output of `nix path-info`: {}", x))?;
+    // Older nix prints a list of objects with a `path`, newer an object keyed by path
+    let info = match &json {
+        Value::Array(x) => x.first(),
+        Value::Object(x) => x.get(path),
+        _ => None,
+    }
+    .ok_or(format!("`nix path-info` printed nothing for {}", path))?;
+
+    let nar_hash = nar_hash(info["narHash"].as_str().unwrap_or_default())?;
+    let nar_size = info["narSize"]
+        .as_u64()
+        .ok_or(format!("no narSize for {}", path))?;
+    let references: Vec<String> = info["references"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.as_str().map(|x| x.to_owned()))
+        .collect();
+    let signatures: Vec<&str> = info["signatures"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.as_str())
+        .collect();
+    if signatures.is_empty() {
+        return Err(format!("{} is not signed", path));
+    }
+    let message = fingerprint(path, &nar_hash, nar_size, &references);
+    signatures
+        .iter()
+        .find_map(|x| verify(trusted, x, message.as_bytes()))
+        .map(|x| x.to_owned())
+        .ok_or(format!("{} is not signed by any of the trusted keys", path))
+}
+
+/// What the signature of a derivation entry is made over. The date only to the second and
+/// the provenance as json, so both survive a round trip through the server.
+pub fn entry_fingerprint(
+    name: &str,
+    branch: &str,
+    store_hash: &str,
+    date_added: Option<DateTime<Local>>,
+    provenance: &Provenance,
+) -> String {
+    format!(
+        "gurl-entry-1;{};{};{};{};{}",
+        name,
+        branch,
+        store_hash,
+        date_added
+            .map(|x| x.timestamp().to_string())
+            .unwrap_or_default(),
+        serde_json::to_string(provenance).expect("Failed to serialize provenance to json.")
+    )
+}
+
+/// Check that `deriv` was signed by a key allowed to publish to its branch and isn't older
+/// than the last applied entry, returning the key's name. Without any publishers configured
+/// nothing is checked.
+pub fn check_entry(deriv: &Deriv, publishers: &[Publisher]) -> Result<Option<String>, String> {
+    if publishers.is_empty() {
+        println!(
+            "WARN: {}",
+            "no publishers configured, not checking who uploaded the derivation".yellow()
+        );
+        return Ok(None);
+    }
+    let signature = deriv.signature.as_deref().ok_or(format!(
+        "{} on the branch {} is not signed",
+        deriv.name, deriv.branch
+    ))?;
+    let allowed = publishers
+        .iter()
+        .filter(|x| x.branches.is_empty() || x.branches.contains(&deriv.branch))
+        .map(|x| PublicKey::parse(&x.key))
+        .collect::<Result<Vec<_>, _>>()?;
+    let message = entry_fingerprint(
+        &deriv.name,
+        &deriv.branch,
+        &deriv.storeHash,
+        deriv.date_added,
+        &deriv.provenance,
+    );
+    let key = verify(&allowed, signature, message.as_bytes()).ok_or(format!(
+        "{} on the branch {} is not signed by a key allowed to publish to {}",
+        deriv.name, deriv.branch, deriv.branch
+    ))?;
+    check_fresh(deriv)?;
+    Ok(Some(key.to_owned()))
+}
+
+/// Date of the newest entry the agent applied, per `<name>/<branch>`. Only root writes it.
+const APPLIED_FILE: &str = "/var/lib/gurl/applied-entries.json";
+
+fn applied_dates() -> HashMap<String, i64> {
+    fs::read_to_string(APPLIED_FILE)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+/// Refuse a signed entry older than the last one applied for the same name and branch,
+/// like an old entry the server replays. Only entries applied by the agent are
+/// remembered, `deriv apply` checks against them but doesn't add to them.
+fn check_fresh(deriv: &Deriv) -> Result<(), String> {
+    let Some(date) = deriv.date_added else {
+        return Err(format!(
+            "{} on the branch {} has no date to check it against the last applied entry",
+            deriv.name, deriv.branch
+        ));
+    };
+    match applied_dates().get(&format!("{}/{}", deriv.name, deriv.branch)) {
+        Some(last) if date.timestamp() < *last => Err(format!(
+            "{} on the branch {} is older than the entry applied last, it may be replayed",
+            deriv.name, deriv.branch
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Remember `deriv` as the newest applied entry of its name and branch.
+pub fn record_applied(deriv: &Deriv) {
+    let Some(date) = deriv.date_added else {
+        return;
+    };
+    let mut dates = applied_dates();
+    dates.insert(format!("{}/{}", deriv.name, deriv.branch), date.timestamp());
+    let result = Path::new(APPLIED_FILE)
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            fs::write(
+                APPLIED_FILE,
+                serde_json::to_string(&dates).expect("Failed to serialize dates to json."),
+            )
+        });
+    if let Err(x) = result {
+        println!(
+            "WARN: {}",
+            format!("saving the applied entry to {}: {}", APPLIED_FILE, x).yellow()
+        );
+    }
+}
+
+/// Refuse `path` unless it's signed by one of the configured `trusted_keys`. Without any
+/// trusted keys configured nothing is checked.
+pub fn check_toplevel(path: &str, trusted_keys: &[String]) -> Result<(), String> {
+    if trusted_keys.is_empty() {
+        println!(
+            "WARN: {}",
+            format!(
+                "no trusted_keys configured, not checking the signature of {}",
+                path
+            )
+            .yellow()
+        );
+        return Ok(());
+    }
+    let trusted = PublicKey::parse_all(trusted_keys)?;
+    let key = verify_store_path(path, &trusted)?;
+    println!("INFO: {} is signed by {}", path, key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{narinfo::NarInfo, store_path::STORE_DIR};
+
+    /// A narinfo as a binary cache serves it, signed by the `test-1` key below.
+    const NARINFO: &str = "\
+StorePath: /nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-hello-2.12.1
+URL: nar/1x5w5f7m0kpfxh2ib0cb1x2ddwrhcghp3z6n6sbq1h8rbjf0r7ny.nar.xz
+Compression: xz
+FileSize: 50160
+NarHash: sha256:1r9hc5vffr6aq9q7pmcdg8j0vxr7kkmj5wz6l37chg8xrbk7ipwp
+NarSize: 226560
+References: 0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-hello-2.12.1 9v5d40jyvmwgnq1nj8f19ji2rcc5dksb-glibc-2.39-52
+Sig: test-1:sYuqXwEttT2AsQ5cc5W/oLlPZiwn27IfilQi613l9a0wdvKHHASRsqUJRoeBTZDph3I1qft+u1TjuFSmlgSNBw==
+";
+    const PUBLIC_KEY: &str = "test-1:GX9rI+FshTLGq8g4+s1ep4m+DHaykgM0A5v6iz02jWE=";
+
+    fn narinfo_fingerprint(info: &NarInfo) -> String {
+        let references: Vec<String> = info
+            .references
+            .iter()
+            .map(|x| format!("{}/{}", STORE_DIR, x))
+            .collect();
+        fingerprint(&info.store_path, &info.nar_hash, info.nar_size, &references)
+    }
+
+    #[test]
+    fn nix_base32_of_sha256() {
+        // sha256 of the empty string, `nix hash file --base32` of an empty file
+        let sha256 = STANDARD
+            .decode("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+            .unwrap();
+        assert_eq!(
+            nix_base32(&sha256),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+        assert_eq!(
+            nar_hash("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap(),
+            "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+
+    #[test]
+    fn fingerprint_sorts_full_references() {
+        let info = NarInfo::parse(NARINFO).unwrap();
+        assert_eq!(
+            narinfo_fingerprint(&info),
+            "1;/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-hello-2.12.1;\
+sha256:1r9hc5vffr6aq9q7pmcdg8j0vxr7kkmj5wz6l37chg8xrbk7ipwp;226560;\
+/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x-hello-2.12.1,\
+/nix/store/9v5d40jyvmwgnq1nj8f19ji2rcc5dksb-glibc-2.39-52"
+        );
+    }
+
+    #[test]
+    fn verify_narinfo_signature() {
+        let info = NarInfo::parse(NARINFO).unwrap();
+        let trusted = PublicKey::parse_all(&[PUBLIC_KEY.to_owned()]).unwrap();
+        let message = narinfo_fingerprint(&info);
+        assert_eq!(
+            verify(&trusted, &info.sigs[0], message.as_bytes()),
+            Some("test-1")
+        );
+
+        let tampered = message.replace(";226560;", ";226561;");
+        assert_eq!(verify(&trusted, &info.sigs[0], tampered.as_bytes()), None);
+        let renamed = info.sigs[0].replace("test-1:", "test-2:");
+        assert_eq!(verify(&trusted, &renamed, message.as_bytes()), None);
+    }
+
+    #[test]
+    fn verify_rfc8032_vector() {
+        // Test 1 of RFC 8032 section 7.1, an empty message
+        let trusted =
+            PublicKey::parse_all(&["rfc:11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=".to_owned()])
+                .unwrap();
+        let signature = "rfc:5VZDAMNgrHKQhuLMgG6CioSHfx645dl02HPgZSJJAVVfuIIVkKM7rMYeOXAc+bRr0lv18FlbviRlUUFDjnoQCw==";
+        assert_eq!(verify(&trusted, signature, b""), Some("rfc"));
+    }
+}
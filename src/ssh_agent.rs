@@ -41,15 +41,13 @@ impl SshAgent {
         match sruct.envs.get("SSH_AGENT_PID") {
             Some(str) => println!("INFO: ssh-agent pid: {}", str),
             None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                return Err(std::io::Error::other(
                     "`ssh-agent` failed to run correctly. - Tami",
                 ));
             }
         }
         if !agent.status.success() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "Creating an `ssh-agent` returned a non-zero exit code. - Tami",
             ));
         }
@@ -63,8 +61,7 @@ impl SshAgent {
         let ssh_add_input = match ssh_add.stdin.as_mut() {
             Some(x) => x,
             None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                return Err(std::io::Error::other(
                     "Could not get mutable stdin of ssh-add. - Tami",
                 ))
             }
@@ -75,20 +72,28 @@ impl SshAgent {
         drop(ssh_add.stdin.take());
 
         if !ssh_add.wait_with_output()?.status.success() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "Running a `ssh-add` returned a non-zero exit code. - Tami",
             ));
         }
-        return Ok(sruct);
+        Ok(sruct)
     }
 
     pub fn add_ssh_opts(&mut self, str: String) {
         self.envs.insert("NIX_SSHOPTS".to_string(), str);
     }
 
-    pub fn run_cmd(&mut self, cmd: &mut Command) -> Result<std::process::Output, std::io::Error> {
-        cmd.envs(&self.envs).output()
+    /// Add the agent's environment to `cmd`.
+    pub fn prepare<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        cmd.envs(&self.envs)
+    }
+
+    /// The options given to `add_ssh_opts`, split up for a plain `ssh` call.
+    pub fn ssh_opts(&self) -> Vec<String> {
+        match self.envs.get("NIX_SSHOPTS") {
+            Some(opts) => opts.split_whitespace().map(|x| x.to_owned()).collect(),
+            None => Vec::new(),
+        }
     }
 }
 
@@ -102,7 +107,7 @@ impl Drop for SshAgent {
                     .output()
                 {
                     Ok(x) => x,
-                    Err(x) => {
+                    Err(_x) => {
                         print_exit(
                             &format!(
                                 "ERROR: {}",
@@ -110,7 +115,6 @@ impl Drop for SshAgent {
                             ),
                             1,
                         );
-                        return;
                     }
                 };
                 if !agent_close.status.success() {
@@ -0,0 +1,149 @@
+use std::fs;
+
+use colored::{ColoredString, Colorize};
+use serde_derive::Serialize;
+
+use crate::{
+    generations, group, json_print, print_exit, reboot, resolve_name, store_path, OutputFormat, DB,
+};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalState {
+    UpToDate,
+    /// The entry is something this host hasn't switched to yet, or rolled back from.
+    Behind,
+    /// The host switched to something newer after it ran the entry.
+    Ahead,
+    /// Running a system the server doesn't know, like one from `nixos-rebuild`.
+    LocallyModified,
+}
+
+impl LocalState {
+    fn colored(&self) -> ColoredString {
+        match self {
+            LocalState::UpToDate => "up to date".green(),
+            LocalState::Behind => "behind".yellow(),
+            LocalState::Ahead => "ahead".cyan(),
+            LocalState::LocallyModified => "locally modified".red(),
+        }
+    }
+
+    /// 0 when up to date, 1 is for errors.
+    fn exit_code(&self) -> i32 {
+        match self {
+            LocalState::UpToDate => 0,
+            LocalState::Behind => 2,
+            LocalState::Ahead => 3,
+            LocalState::LocallyModified => 4,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    name: String,
+    branch: String,
+    expected: String,
+    running: String,
+    booted: Option<String>,
+    generation: Option<u32>,
+    state: LocalState,
+    /// The expected system is already in the local store.
+    cached: bool,
+    reboot_required: bool,
+}
+
+/// Show whether this host runs its entry. The exit code is 0 when up to date, 2 when
+/// behind, 3 when ahead and 4 when locally modified.
+pub fn handle_deriv_status(output: OutputFormat, name: String, branch: String) {
+    let name = resolve_name(name);
+    let deriv = match group::resolve_entry(&name, &branch) {
+        Ok(x) => x,
+        Err(x) => print_exit(&format!("ERROR: {}", x.red()), 1),
+    };
+    if output == OutputFormat::Table {
+        group::print_follows(&name, &deriv);
+    }
+    let read_system = |x: &str| {
+        fs::read_link(x)
+            .ok()
+            .map(|x| x.to_string_lossy().into_owned())
+    };
+    let running = match read_system("/run/current-system") {
+        Some(x) => x,
+        None => print_exit(
+            &format!("ERROR: {}", "could not read /run/current-system".red()),
+            1,
+        ),
+    };
+
+    let generations = generations::list();
+    let last_gen_of = |path: &str| {
+        generations
+            .iter()
+            .filter(|x| x.path == path)
+            .map(|x| x.number)
+            .max()
+    };
+    let running_gen = last_gen_of(&running).or(generations::current());
+    let state = if running == deriv.storeHash {
+        LocalState::UpToDate
+    } else if !DB::get_all()
+        .unwrap_or_else(|| print_exit(&format!("ERROR: {}", "Failed to get entries".red()), 1))
+        .iter()
+        .any(|x| x.storeHash == running)
+    {
+        LocalState::LocallyModified
+    } else {
+        match (last_gen_of(&deriv.storeHash), running_gen) {
+            (Some(expected), Some(running)) if expected < running => LocalState::Ahead,
+            _ => LocalState::Behind,
+        }
+    };
+
+    let status = Status {
+        name: deriv.name,
+        branch: deriv.branch,
+        cached: store_path::is_valid(&deriv.storeHash),
+        expected: deriv.storeHash,
+        running,
+        booted: read_system("/run/booted-system"),
+        generation: running_gen,
+        state,
+        reboot_required: !reboot::changed_components().is_empty(),
+    };
+    if output == OutputFormat::Json {
+        json_print(&status);
+        std::process::exit(state.exit_code());
+    }
+
+    println!(
+        "{} ({}): {}",
+        status.name.green(),
+        status.branch,
+        state.colored()
+    );
+    println!("\texpected: {}", status.expected);
+    println!("\trunning: {}", status.running);
+    if let Some(booted) = status.booted.as_ref().filter(|x| **x != status.running) {
+        println!("\tbooted: {}", booted);
+    }
+    if let Some(generation) = status.generation {
+        println!("\tgeneration: {}", generation);
+    }
+    if state != LocalState::UpToDate {
+        println!(
+            "\tcached: {}",
+            if status.cached {
+                "yes, `gurl deriv apply` won't download anything".green()
+            } else {
+                "no".normal()
+            }
+        );
+    }
+    if status.reboot_required {
+        println!("\t{}", "reboot required".yellow());
+    }
+    std::process::exit(state.exit_code());
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt, fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use colored::Colorize;
+
+pub const STORE_DIR: &str = "/nix/store";
+
+/// Characters of nix's base32, which leaves out `e`, `o`, `u` and `t`.
+pub const NIX_BASE32: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+const HASH_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 211;
+
+/// A validated `/nix/store/<hash>-<name>` path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorePath {
+    pub hash: String,
+    pub name: String,
+}
+
+impl StorePath {
+    pub fn parse(path: &str) -> Result<StorePath, String> {
+        let base = path
+            .strip_prefix(STORE_DIR)
+            .and_then(|x| x.strip_prefix('/'))
+            .ok_or(format!("{} is not in {}", path, STORE_DIR))?;
+        let base = base.strip_suffix('/').unwrap_or(base);
+        if base.contains('/') {
+            return Err(format!("{} is inside a store path, not one", path));
+        }
+        let (hash, name) = base
+            .split_once('-')
+            .ok_or(format!("{} has no name after its hash", path))?;
+        if hash.len() != HASH_LEN || !hash.chars().all(|x| NIX_BASE32.contains(x)) {
+            return Err(format!("{} is not a valid nix base32 hash", hash));
+        }
+        let valid_name = |x: char| x.is_ascii_alphanumeric() || "+-._?=".contains(x);
+        if name.is_empty()
+            || name.len() > MAX_NAME_LEN
+            || name.starts_with('.')
+            || !name.chars().all(valid_name)
+        {
+            return Err(format!("{} is not a valid store path name", name));
+        }
+        Ok(StorePath {
+            hash: hash.to_owned(),
+            name: name.to_owned(),
+        })
+    }
+
+    /// Whether this looks like `config.system.build.toplevel` of a NixOS system.
+    /// Only works if the path is in the local store.
+    pub fn is_nixos_toplevel(&self) -> bool {
+        let path = self.to_string();
+        let path = Path::new(&path);
+        path.join("nixos-version").exists() && path.join("bin/switch-to-configuration").exists()
+    }
+}
+
+/// Whether `path` is completely in the local store, not just there on disk.
+pub fn is_valid(path: &str) -> bool {
+    Path::new(path).exists()
+        && Command::new("nix-store")
+            .args(["--check-validity", path])
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|x| x.success())
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}-{}", STORE_DIR, self.hash, self.name)
+    }
+}
+
+fn build_flake_ref(installable: &str) -> Result<String, String> {
+    println!("INFO: building {}", installable);
+    let out = Command::new("nix")
+        .args(["build", "--no-link", "--print-out-paths", installable])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|x| format!("could not run `nix build`: {}", x))?;
+    if !out.status.success() {
+        return Err(format!("`nix build {}` failed", installable));
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .map(|x| x.to_owned())
+        .ok_or(format!("`nix build {}` printed no path", installable))
+}
+
+/// Turn what the user typed(a store path, `./result`, a relative path or a flake
+/// installable like `.#nixosConfigurations.x.config.system.build.toplevel`) into a store path.
+/// Anything but a NixOS system is refused unless `any_path` is set. Store paths are taken
+/// as they are, they don't have to be in the local store.
+pub fn resolve(input: &str, any_path: bool) -> Result<StorePath, String> {
+    let path = if input.contains('#') {
+        build_flake_ref(input)?
+    } else if input.starts_with(STORE_DIR) {
+        input.to_owned()
+    } else {
+        fs::canonicalize(input)
+            .map_err(|x| format!("{}: {}", input, x))?
+            .to_string_lossy()
+            .into_owned()
+    };
+    let store_path = StorePath::parse(&path)?;
+    if any_path {
+        return Ok(store_path);
+    }
+    if !Path::new(&store_path.to_string()).exists() {
+        println!(
+            "WARN: {}",
+            format!(
+                "{} is not in the local store, not checking that it is a NixOS system",
+                store_path
+            )
+            .yellow()
+        );
+    } else if !store_path.is_nixos_toplevel() {
+        return Err(format!(
+            "{} is not a NixOS system toplevel, pass --any-path to upload it anyway",
+            store_path
+        ));
+    }
+    Ok(store_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1x";
+
+    #[test]
+    fn parse_store_paths() {
+        let path =
+            StorePath::parse(&format!("/nix/store/{}-nixos-system-web-24.05", HASH)).unwrap();
+        assert_eq!(path.hash, HASH);
+        assert_eq!(path.name, "nixos-system-web-24.05");
+        assert_eq!(
+            path.to_string(),
+            format!("/nix/store/{}-nixos-system-web-24.05", HASH)
+        );
+        // A trailing slash, like shell completion adds
+        assert!(StorePath::parse(&format!("/nix/store/{}-hello/", HASH)).is_ok());
+    }
+
+    #[test]
+    fn parse_invalid_store_paths() {
+        let long_name = "a".repeat(MAX_NAME_LEN + 1);
+        let invalid = [
+            format!("/nix/stor/{}-hello", HASH),
+            format!("nix/store/{}-hello", HASH),
+            format!("/nix/store/{}-hello/bin/hello", HASH),
+            format!("/nix/store/{}", HASH),
+            format!("/nix/store/{}-", HASH),
+            // Too short, and letters that aren't in nix base32
+            "/nix/store/0c7c8l4zbwz7gyk3f4hz8x-hello".to_owned(),
+            "/nix/store/0c7c8l4zbwz7gyk3f4hz8ximm8k1jw1e-hello".to_owned(),
+            format!("/nix/store/{}-.hello", HASH),
+            format!("/nix/store/{}-hello world", HASH),
+            format!("/nix/store/{}-{}", HASH, long_name),
+        ];
+        for path in invalid {
+            assert!(StorePath::parse(&path).is_err(), "{}", path);
+        }
+    }
+}
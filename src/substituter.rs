@@ -0,0 +1,111 @@
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+
+use crate::{
+    narinfo::{self, human_size, is_http, Missing, NarInfo},
+    progress,
+    store_path::StorePath,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `uri` took to say it has `path`, `None` if it doesn't or couldn't be reached.
+fn probe(uri: &str, path: &str) -> Option<Duration> {
+    let start = Instant::now();
+    let has_path = match StorePath::parse(path) {
+        Ok(store_path) if is_http(uri) => {
+            matches!(NarInfo::fetch(uri, &store_path.hash), Ok(Some(_)))
+        }
+        _ => Command::new("timeout")
+            .arg(PROBE_TIMEOUT.as_secs().to_string())
+            .args(["nix", "path-info", "--store", uri, path])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|x| x.success()),
+    };
+    has_path.then(|| start.elapsed())
+}
+
+/// The substituters that have `path`, fastest first. All of them are probed at once.
+pub fn rank(substituters: &[String], path: &str) -> Vec<String> {
+    let mut found: Vec<(Duration, String)> = thread::scope(|scope| {
+        let probes: Vec<_> = substituters
+            .iter()
+            .map(|uri| scope.spawn(move || probe(uri, path).map(|x| (x, uri.clone()))))
+            .collect();
+        probes
+            .into_iter()
+            .filter_map(|x| x.join().ok().flatten())
+            .collect()
+    });
+    found.sort_by_key(|x| x.0);
+    for uri in substituters {
+        if !found.iter().any(|x| &x.1 == uri) {
+            println!(
+                "WARN: {}",
+                format!("{} doesn't have {}", uri, path).yellow()
+            );
+        }
+    }
+    found.into_iter().map(|x| x.1).collect()
+}
+
+/// Copy the closure of `path` from the fastest substituter that has it, going down the
+/// list if a copy fails. The download `estimate` is walked here when not given.
+pub fn copy_from(
+    substituters: &[String],
+    path: &str,
+    estimate: Option<&Result<Missing, String>>,
+) -> bool {
+    let ranked = rank(substituters, path);
+    if ranked.is_empty() {
+        println!(
+            "ERROR: {}",
+            format!("none of the substituters have {}", path).red()
+        );
+        return false;
+    }
+    let walked;
+    let estimate = match estimate {
+        Some(x) => Some(x),
+        None if is_http(&ranked[0]) => {
+            walked = narinfo::missing_closure(&ranked[0], path);
+            Some(&walked)
+        }
+        None => None,
+    };
+    if let Some(estimate) = estimate {
+        match estimate {
+            Ok(x) => println!(
+                "INFO: {} paths to download, {} ({} unpacked)",
+                x.paths,
+                human_size(x.download_size),
+                human_size(x.nar_size)
+            ),
+            Err(x) => println!("WARN: {}", format!("no download estimate, {}", x).yellow()),
+        }
+    }
+    for uri in ranked {
+        println!("INFO: copying the closure from {}", uri.green());
+        let status = progress::run_nix(Command::new("nix").args(["copy", "--from", &uri, path]));
+        match status {
+            Ok(x) if x.success() => {
+                println!("INFO: Closure has finished copying from {}", uri);
+                return true;
+            }
+            Ok(_) => println!("WARN: {}", format!("copying from {} failed", uri).yellow()),
+            Err(_) => {
+                println!("ERROR: {}", "Failed to start closure copy!".red());
+                return false;
+            }
+        }
+    }
+    println!("ERROR: {}", "Error during closure copy!".red());
+    false
+}